@@ -8,17 +8,17 @@ use std::{
 };
 
 use mate_solver::{
-    df_pn::search as dfpnsearch,
+    df_pn::{parallel::df_pn_parallel, search as dfpnsearch},
     eval::{Value, search as evalsearch},
+    leaf_init::{LeafInitMode, LeafInitOptions},
+    move_ordering::MoveOrderingOptions,
     position_wrapper::PositionWrapper,
-    tt::{DfPnTable, EvalTable},
+    tt::{DEFAULT_HASH_MB, DfPnTable, EvalTable, TtStats},
 };
 use serde_json::{Value as JsonValue, json};
 use shogi_core::PartialPosition;
 use shogi_usi_parser::FromUsi;
 
-const TABLE_SIZE: usize = 1 << 16;
-
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 enum Expected {
     Mate,
@@ -120,7 +120,7 @@ fn run() -> Result<(), ()> {
 fn print_usage() {
     eprintln!("usage:");
     eprintln!(
-        "  benchmark_harness run [--strict] [--verbose] [--revision=<label>] <positions.jsonl>..."
+        "  benchmark_harness run [--strict] [--verbose] [--revision=<label>] [--leaf-init=<uniform|mobility>] [--threads=<n>] [--hash=<MB>] <positions.jsonl>..."
     );
     eprintln!(
         "  benchmark_harness compare --base <base.jsonl> --current <current.jsonl> [--html <report.html>]"
@@ -131,6 +131,9 @@ fn run_benchmark(args: &[String]) -> Result<(), ()> {
     let mut revision = "current".to_owned();
     let mut strict = false;
     let mut verbose = false;
+    let mut leaf_init = LeafInitOptions::default();
+    let mut threads = 1;
+    let mut hash_mb = DEFAULT_HASH_MB;
     let mut inputs = Vec::new();
 
     for arg in args {
@@ -140,6 +143,27 @@ fn run_benchmark(args: &[String]) -> Result<(), ()> {
             verbose = true;
         } else if let Some(rest) = arg.strip_prefix("--revision=") {
             revision = rest.to_owned();
+        } else if let Some(rest) = arg.strip_prefix("--leaf-init=") {
+            let Some(mode) = LeafInitMode::parse(rest) else {
+                eprintln!("unknown leaf initialisation policy: {rest}");
+                print_usage();
+                return Err(());
+            };
+            leaf_init.mode = mode;
+        } else if let Some(rest) = arg.strip_prefix("--threads=") {
+            let Some(value) = rest.parse().ok().filter(|&value| value >= 1) else {
+                eprintln!("invalid thread count: {rest}");
+                print_usage();
+                return Err(());
+            };
+            threads = value;
+        } else if let Some(rest) = arg.strip_prefix("--hash=") {
+            let Some(value) = rest.parse().ok().filter(|&value| value >= 1) else {
+                eprintln!("invalid hash size: {rest}");
+                print_usage();
+                return Err(());
+            };
+            hash_mb = value;
         } else {
             inputs.push(arg.clone());
         }
@@ -156,7 +180,10 @@ fn run_benchmark(args: &[String]) -> Result<(), ()> {
             "type": "metadata",
             "mode": "run",
             "revision": revision,
-        "inputs": &inputs,
+            "leaf_init": leaf_init.mode.as_str(),
+            "threads": threads,
+            "hash_mb": hash_mb,
+            "inputs": &inputs,
         })
     );
 
@@ -191,7 +218,8 @@ fn run_benchmark(args: &[String]) -> Result<(), ()> {
                     continue;
                 }
             };
-            if let Err(message) = evaluate_position(&record, verbose) {
+            if let Err(message) = evaluate_position(&record, verbose, &leaf_init, threads, hash_mb)
+            {
                 emit_error(&input, line_number, "evaluate", message, &raw_line);
                 failed = true;
             }
@@ -254,24 +282,44 @@ fn parse_position_record(
     })
 }
 
-fn evaluate_position(record: &PositionRecord, verbose: bool) -> Result<(), String> {
+fn evaluate_position(
+    record: &PositionRecord,
+    verbose: bool,
+    leaf_init: &LeafInitOptions,
+    threads: usize,
+    hash_mb: usize,
+) -> Result<(), String> {
     let position = PartialPosition::from_usi(&format!("sfen {}", record.sfen))
         .map_err(|error| format!("invalid SFEN: {error:?}"))?;
-    evaluate_df_pn(record, &position, verbose);
-    evaluate_eval(record, &position, verbose);
+    mate_solver::validate::validate(&position)
+        .map_err(|error| format!("invalid position: {}", error.reason))?;
+    evaluate_df_pn(record, &position, verbose, leaf_init, threads, hash_mb);
+    evaluate_eval(record, &position, verbose, leaf_init, threads, hash_mb);
     Ok(())
 }
 
-fn evaluate_df_pn(record: &PositionRecord, position: &PartialPosition, verbose: bool) {
-    let mut df_pn = DfPnTable::new(TABLE_SIZE);
+fn evaluate_df_pn(
+    record: &PositionRecord,
+    position: &PartialPosition,
+    verbose: bool,
+    leaf_init: &LeafInitOptions,
+    threads: usize,
+    hash_mb: usize,
+) {
+    let df_pn = DfPnTable::with_megabytes(hash_mb);
     let mut stats = dfpnsearch::SearchStats::default();
     let started = Instant::now();
-    let (proof_number, disproof_number) = dfpnsearch::df_pn_with_stats(
-        &mut df_pn,
+    let (proof_number, disproof_number) = df_pn_parallel(
+        &df_pn,
         &PositionWrapper::new(position.clone()),
         verbose,
         &mut stats,
-    );
+        &MoveOrderingOptions::default(),
+        leaf_init,
+        &dfpnsearch::SearchLimits::default(),
+        threads,
+    )
+    .value();
     let elapsed_ms = started.elapsed().as_secs_f64() * 1000.0;
     let resolution = if (proof_number, disproof_number) == (u32::MAX, 0) {
         Expected::NoMate
@@ -288,6 +336,8 @@ fn evaluate_df_pn(record: &PositionRecord, position: &PartialPosition, verbose:
             "evaluator": "df_pn",
             "elapsed_ms": elapsed_ms,
             "positions_inspected": stats.positions_inspected,
+            "hashfull": stats.hashfull,
+            "tt": tt_json(&stats.tt),
             "resolution": resolution.as_str(),
             "expected": record.expected.map(Expected::as_str),
             "correct": record.expected.map(|expected| expected == resolution),
@@ -297,16 +347,27 @@ fn evaluate_df_pn(record: &PositionRecord, position: &PartialPosition, verbose:
     );
 }
 
-fn evaluate_eval(record: &PositionRecord, position: &PartialPosition, verbose: bool) {
-    let mut df_pn = DfPnTable::new(TABLE_SIZE);
-    let mut eval = EvalTable::new(TABLE_SIZE);
+fn evaluate_eval(
+    record: &PositionRecord,
+    position: &PartialPosition,
+    verbose: bool,
+    leaf_init: &LeafInitOptions,
+    threads: usize,
+    hash_mb: usize,
+) {
+    let mut df_pn = DfPnTable::with_megabytes(hash_mb);
+    let mut eval = EvalTable::with_megabytes(hash_mb);
     let mut seed_stats = dfpnsearch::SearchStats::default();
     let mut eval_stats = evalsearch::SearchStats::default();
-    dfpnsearch::df_pn_with_stats(
-        &mut df_pn,
+    df_pn_parallel(
+        &df_pn,
         &PositionWrapper::new(position.clone()),
         verbose,
         &mut seed_stats,
+        &MoveOrderingOptions::default(),
+        leaf_init,
+        &dfpnsearch::SearchLimits::default(),
+        threads,
     );
     let mut df_pn_stats = dfpnsearch::SearchStats::default();
     let started = Instant::now();
@@ -340,6 +401,10 @@ fn evaluate_eval(record: &PositionRecord, position: &PartialPosition, verbose: b
             "evaluator": "eval",
             "elapsed_ms": elapsed_ms,
             "positions_inspected": eval_stats.positions_inspected + df_pn_stats.positions_inspected,
+            "hashfull": eval_stats.hashfull,
+            "df_pn_hashfull": df_pn_stats.hashfull,
+            "tt": tt_json(&eval_stats.tt),
+            "df_pn_tt": tt_json(&df_pn_stats.tt),
             "resolution": resolution.as_str(),
             "expected": record.expected.map(Expected::as_str),
             "expected_plies": record.expected_plies,
@@ -358,6 +423,16 @@ fn value_json(value: Value) -> JsonValue {
     })
 }
 
+fn tt_json(stats: &TtStats) -> JsonValue {
+    json!({
+        "probes": stats.probes,
+        "hits": stats.hits,
+        "misses": stats.misses,
+        "replacements": stats.replacements,
+        "bucket_full": stats.bucket_full,
+    })
+}
+
 fn emit_error(source: &str, line: u64, stage: &str, message: String, raw_line: &str) {
     println!(
         "{}",
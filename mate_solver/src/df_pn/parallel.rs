@@ -0,0 +1,173 @@
+//! 複数のスレッドで置換表を共有する df-pn。
+//!
+//! すべてのスレッドが同じ局面をルートから探索し、置換表を通して結果を共有する。
+//! 2 番目以降のスレッドは値の同じ子ノードから乱数で選んで、別の部分木を先に調べる。
+use std::{sync::Arc, thread};
+
+use crate::{
+    leaf_init::LeafInitOptions, move_ordering::MoveOrderingOptions,
+    position_wrapper::PositionWrapper, tt::DfPnTable,
+};
+
+use super::search::{
+    df_pn_with_limits, mid_with_options_and_stats, NodeKind, Outcome, SearchCtx, SearchLimits,
+    SearchStats, SharedProgress, INF,
+};
+
+/// threads 個のスレッドで探索する。どれかのスレッドが詰みか不詰を示した時点で全体を打ち切る。
+///
+/// limits の局面数の上限は全スレッドの合計に適用される。stats には全スレッドの合計を加える。
+#[allow(clippy::too_many_arguments)]
+pub fn df_pn_parallel(
+    dfpn_tbl: &DfPnTable,
+    position: &PositionWrapper,
+    verbose: bool,
+    stats: &mut SearchStats,
+    move_ordering: &MoveOrderingOptions,
+    leaf_init: &LeafInitOptions,
+    limits: &SearchLimits,
+    threads: usize,
+) -> Outcome {
+    if threads <= 1 {
+        return df_pn_with_limits(
+            dfpn_tbl,
+            position,
+            verbose,
+            stats,
+            move_ordering,
+            leaf_init,
+            limits,
+        );
+    }
+    let shared = Arc::new(SharedProgress::default());
+    let worker_limits = SearchLimits {
+        shared: Some(shared.clone()),
+        ..limits.clone()
+    };
+    let results: Vec<(Outcome, SearchStats)> = thread::scope(|scope| {
+        let handles: Vec<_> = (0..threads)
+            .map(|index| {
+                let shared = &shared;
+                let worker_limits = &worker_limits;
+                scope.spawn(move || {
+                    let mut ctx = if index == 0 {
+                        SearchCtx::default()
+                    } else {
+                        SearchCtx::with_seed(index as u64)
+                    };
+                    let mut stats = SearchStats::default();
+                    let outcome: Outcome = mid_with_options_and_stats(
+                        dfpn_tbl,
+                        position,
+                        (INF, INF),
+                        NodeKind::Or,
+                        &mut ctx,
+                        verbose && index == 0,
+                        &mut stats,
+                        move_ordering,
+                        leaf_init,
+                        worker_limits,
+                    )
+                    .into();
+                    if !matches!(outcome, Outcome::Unknown(_)) {
+                        shared.finish();
+                    }
+                    (outcome, stats)
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .collect()
+    });
+    for (_, worker_stats) in &results {
+        stats.positions_inspected += worker_stats.positions_inspected;
+    }
+    stats.hashfull = dfpn_tbl.hashfull();
+    stats.tt = dfpn_tbl.stats();
+    results
+        .iter()
+        .map(|&(outcome, _)| outcome)
+        .find(|outcome| !matches!(outcome, Outcome::Unknown(_)))
+        .unwrap_or(results[0].0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shogi_core::PartialPosition;
+    use shogi_usi_parser::FromUsi;
+
+    #[test]
+    fn parallel_search_agrees_with_single_thread() {
+        let cases = [
+            (
+                "5kgnl/9/4+B1pp1/8p/9/9/9/9/9 b 2S2rb3g2s3n3l15p 1",
+                Outcome::Mate,
+            ),
+            (
+                "7nk/9/6PB1/6NP1/9/9/9/9/9 b P2rb4g4s2n4l15p 1",
+                Outcome::Mate,
+            ),
+            ("8k/9/9/9/9/9/9/9/9 b Rr2b4g4s4n4l18p 1", Outcome::NoMate),
+        ];
+        for (sfen, expected) in cases {
+            let position = PartialPosition::from_usi(&format!("sfen {}", sfen)).unwrap();
+            let dfpn_tbl = DfPnTable::new(1 << 16);
+            let mut stats = SearchStats::default();
+            let outcome = df_pn_parallel(
+                &dfpn_tbl,
+                &PositionWrapper::new(position),
+                false,
+                &mut stats,
+                &MoveOrderingOptions::default(),
+                &LeafInitOptions::default(),
+                &SearchLimits::default(),
+                4,
+            );
+            assert_eq!(outcome, expected, "{}", sfen);
+            assert!(stats.positions_inspected > 0);
+            assert!(stats.tt.probes > 0);
+            assert_eq!(stats.tt.probes, stats.tt.hits + stats.tt.misses);
+        }
+    }
+
+    #[test]
+    fn limits_apply_to_all_threads() {
+        use std::sync::atomic::AtomicBool;
+
+        let position =
+            PartialPosition::from_usi("sfen 8k/9/9/9/9/9/9/9/9 b Rr2b4g4s4n4l18p 1").unwrap();
+        let position = PositionWrapper::new(position);
+        let run = |limits: &SearchLimits| {
+            let dfpn_tbl = DfPnTable::new(1 << 16);
+            let mut stats = SearchStats::default();
+            let outcome = df_pn_parallel(
+                &dfpn_tbl,
+                &position,
+                false,
+                &mut stats,
+                &MoveOrderingOptions::default(),
+                &LeafInitOptions::default(),
+                limits,
+                4,
+            );
+            (outcome, stats.positions_inspected)
+        };
+
+        // 局面数の上限はスレッドごとではなく合計に適用される。
+        let (outcome, nodes) = run(&SearchLimits {
+            max_nodes: Some(100),
+            ..Default::default()
+        });
+        assert!(matches!(outcome, Outcome::Unknown(_)));
+        assert!(nodes <= 100 + 4, "{}", nodes);
+
+        let (outcome, _) = run(&SearchLimits {
+            cancel: Some(Arc::new(AtomicBool::new(true))),
+            ..Default::default()
+        });
+        assert!(matches!(outcome, Outcome::Unknown(_)));
+    }
+}
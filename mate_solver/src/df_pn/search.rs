@@ -1,11 +1,24 @@
 // 長井, 今井: df-pnアルゴリズムの詰将棋を解くプログラムへの応用.
 
-use shogi_core::{Move, Square};
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use shogi_core::{Hand, Move, PieceKind};
+use shogi_legality_lite::is_in_check_partial_lite;
 
 use crate::{
+    hand,
+    leaf_init::{self, LeafInitOptions},
     move_ordering::{order_df_pn_moves, MoveOrderingOptions},
     position_wrapper::{Key, PositionWrapper},
-    tt::DfPnTable,
+    tt::{DfPnEntry, DfPnKey, DfPnTable, PathDependency, TtStats},
 };
 
 #[derive(Clone, Copy)]
@@ -14,6 +27,10 @@ pub enum NodeKind {
     Or,
     /// 玉方
     And,
+    /// 自玉詰の攻め方。王手をかけ続けて、玉方に自分の玉を詰まさせる。
+    SelfOr,
+    /// 自玉詰の玉方。攻め方の玉を詰まさないように王手を外す。
+    SelfAnd,
 }
 
 impl NodeKind {
@@ -21,27 +38,212 @@ impl NodeKind {
         match self {
             NodeKind::Or => NodeKind::And,
             NodeKind::And => NodeKind::Or,
+            NodeKind::SelfOr => NodeKind::SelfAnd,
+            NodeKind::SelfAnd => NodeKind::SelfOr,
+        }
+    }
+
+    /// 攻め方の手番か。
+    pub fn is_attacker(self) -> bool {
+        matches!(self, NodeKind::Or | NodeKind::SelfOr)
+    }
+
+    /// 自玉詰のノードか。
+    pub fn is_selfmate(self) -> bool {
+        matches!(self, NodeKind::SelfOr | NodeKind::SelfAnd)
+    }
+
+    /// df-pn の置換表を引くための鍵。
+    ///
+    /// 自玉詰では持ち駒が多いほど攻め方に有利とは限らない (合駒ができて詰まなくなる) ので、
+    /// 持ち駒の優越関係を使わないように、持ち駒まで含めたハッシュ値を盤面の鍵にする。
+    pub fn key(self, position: &PositionWrapper) -> DfPnKey {
+        let key = position.df_pn_key();
+        if self.is_selfmate() {
+            DfPnKey {
+                board: position.zobrist_hash() ^ SELFMATE_SALT,
+                ..key
+            }
+        } else {
+            key
         }
     }
 }
 
+// 自玉詰の鍵が詰将棋の鍵と重ならないようにする。
+const SELFMATE_SALT: Key = 0x5e1f_3a7e_9d1c_b2a5 as Key;
+
+/// 証明数・反証数の無限大。
+pub const INF: u32 = u32::MAX;
+
 #[derive(Clone, Default)]
 pub struct SearchCtx {
     seq: Vec<Move>,
+    /// 探索経路上の局面の (ハッシュ値, 経路のハッシュ値)。現在の局面を含む。
+    path: Vec<(Key, Key)>,
+    /// 展開中のノードのスタック。呼び出しをまたいで使い回す。
+    frames: Vec<Frame>,
+    /// 子ノードを選ぶときの同点を乱数で決める。なければ先に並んでいる方を選ぶ。
+    rng: Option<ChaCha8Rng>,
+    /// 打ち切り条件に達したか。ノードを展開するときに調べる。
+    stopped: bool,
 }
 
 #[derive(Clone, Copy, Debug, Default)]
 pub struct SearchStats {
     pub positions_inspected: u64,
+    /// 探索を終えたときの置換表の使用率 (千分率)
+    pub hashfull: u32,
+    /// 探索を終えたときの置換表の利用状況 (表を作ってからの累計)
+    pub tt: TtStats,
+}
+
+/// 打ち切る時刻を調べる間隔 (局面数)。
+pub const DEADLINE_CHECK_INTERVAL: u64 = 1024;
+
+/// 探索の打ち切り条件。既定値では打ち切らない。
+#[derive(Clone, Debug, Default)]
+pub struct SearchLimits {
+    /// 調べる局面数の上限
+    pub max_nodes: Option<u64>,
+    /// 探索を打ち切る時刻
+    pub deadline: Option<Instant>,
+    /// 外部から true にされたら探索を打ち切る
+    pub cancel: Option<Arc<AtomicBool>>,
+    /// 並列探索のスレッドで共有する進み具合。あれば局面数の上限は全スレッドの合計に適用する。
+    pub shared: Option<Arc<SharedProgress>>,
+}
+
+/// 並列探索のスレッドで共有する探索の進み具合。
+#[derive(Debug, Default)]
+pub struct SharedProgress {
+    /// 全スレッドで調べた局面数
+    nodes: AtomicU64,
+    /// どれかのスレッドが詰みか不詰を示した
+    finished: AtomicBool,
+}
+
+impl SharedProgress {
+    /// 全スレッドで調べた局面数
+    pub fn nodes(&self) -> u64 {
+        self.nodes.load(Ordering::Relaxed)
+    }
+
+    /// 他のスレッドの探索を打ち切らせる。
+    pub fn finish(&self) {
+        self.finished.store(true, Ordering::Relaxed);
+    }
+}
+
+impl SearchLimits {
+    /// 打ち切り条件に達したか。
+    pub fn is_reached(&self, stats: &SearchStats) -> bool {
+        self.is_reached_at(stats.positions_inspected)
+    }
+
+    /// 調べた局面数が nodes のときに打ち切り条件に達したか。df-pn 以外の探索でも使う。
+    ///
+    /// 局面を 1 つ調べるごとに 1 回呼ぶ。時刻は最初の局面と、以後 [`DEADLINE_CHECK_INTERVAL`] 局面ごとに調べる。
+    pub fn is_reached_at(&self, nodes: u64) -> bool {
+        // 時刻の取得は局面を調べるのに比べて重いので、間引いて調べる。
+        let check_deadline = nodes % DEADLINE_CHECK_INTERVAL == 1;
+        let nodes = match &self.shared {
+            Some(shared) => {
+                if shared.finished.load(Ordering::Relaxed) {
+                    return true;
+                }
+                shared.nodes()
+            }
+            None => nodes,
+        };
+        if let Some(max_nodes) = self.max_nodes {
+            if nodes >= max_nodes {
+                return true;
+            }
+        }
+        if let Some(cancel) = &self.cancel {
+            if cancel.load(Ordering::Relaxed) {
+                return true;
+            }
+        }
+        if let Some(deadline) = self.deadline {
+            if check_deadline && Instant::now() >= deadline {
+                return true;
+            }
+        }
+        false
+    }
+
+    // 局面を 1 つ調べたことを、並列探索の他のスレッドと共有する。
+    #[inline(always)]
+    fn record_node(&self) {
+        if let Some(shared) = &self.shared {
+            shared.nodes.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// ルートでの探索結果。
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Outcome {
+    /// 詰み
+    Mate,
+    /// 不詰
+    NoMate,
+    /// 打ち切り条件に達して分からなかった。(phi, delta) は打ち切った時点の値。
+    Unknown((u32, u32)),
+}
+
+impl Outcome {
+    /// ルートの (phi, delta)。
+    pub fn value(self) -> (u32, u32) {
+        match self {
+            Outcome::Mate => (0, INF),
+            Outcome::NoMate => (INF, 0),
+            Outcome::Unknown(value) => value,
+        }
+    }
+}
+
+impl From<(u32, u32)> for Outcome {
+    fn from(value: (u32, u32)) -> Self {
+        match value {
+            (0, _) => Outcome::Mate,
+            (_, 0) => Outcome::NoMate,
+            value => Outcome::Unknown(value),
+        }
+    }
 }
 
 impl SearchCtx {
+    /// 値の同じ子ノードから seed で決まる乱数で選んで探索する。並列探索でスレッドごとに異なる部分木を探すのに使う。
+    pub fn with_seed(seed: u64) -> Self {
+        Self {
+            rng: Some(ChaCha8Rng::seed_from_u64(seed)),
+            ..Default::default()
+        }
+    }
+
     pub fn push(&mut self, mv: Move) {
         self.seq.push(mv);
     }
     pub fn pop(&mut self) {
         self.seq.pop();
     }
+
+    /// 現在の経路に局面 hash をつなげた経路のハッシュ値。
+    fn path_key_after(&self, hash: Key) -> Key {
+        let last = self.path.last().map_or(0, |&(_, path_key)| path_key);
+        last.rotate_left(1) ^ hash
+    }
+
+    /// 局面 hash が探索経路上に現れる位置。
+    fn repetition(&self, hash: Key) -> Option<u32> {
+        self.path
+            .iter()
+            .rposition(|&(key, _)| key == hash)
+            .map(|index| index as u32)
+    }
 }
 
 impl core::fmt::Debug for SearchCtx {
@@ -56,13 +258,13 @@ impl core::fmt::Debug for SearchCtx {
     }
 }
 
-// ルートでの反復深化
-pub fn df_pn(dfpn_tbl: &mut DfPnTable, position: &PositionWrapper, verbose: bool) -> (u32, u32) {
+// ルートでの反復深化
+pub fn df_pn(dfpn_tbl: &DfPnTable, position: &PositionWrapper, verbose: bool) -> (u32, u32) {
     df_pn_with_options(dfpn_tbl, position, verbose, &MoveOrderingOptions::default())
 }
 
 pub fn df_pn_with_options(
-    dfpn_tbl: &mut DfPnTable,
+    dfpn_tbl: &DfPnTable,
     position: &PositionWrapper,
     verbose: bool,
     move_ordering: &MoveOrderingOptions,
@@ -73,12 +275,13 @@ pub fn df_pn_with_options(
         verbose,
         &mut SearchStats::default(),
         move_ordering,
+        &LeafInitOptions::default(),
     )
 }
 
 // Root iterative deepening with stats collection.
 pub fn df_pn_with_stats(
-    dfpn_tbl: &mut DfPnTable,
+    dfpn_tbl: &DfPnTable,
     position: &PositionWrapper,
     verbose: bool,
     stats: &mut SearchStats,
@@ -89,54 +292,99 @@ pub fn df_pn_with_stats(
         verbose,
         stats,
         &MoveOrderingOptions::default(),
+        &LeafInitOptions::default(),
     )
 }
 
 pub fn df_pn_with_options_and_stats(
-    dfpn_tbl: &mut DfPnTable,
+    dfpn_tbl: &DfPnTable,
     position: &PositionWrapper,
     verbose: bool,
     stats: &mut SearchStats,
     move_ordering: &MoveOrderingOptions,
+    leaf_init: &LeafInitOptions,
 ) -> (u32, u32) {
-    let (phi_now, delta_now) = mid_with_options_and_stats(
+    // 千日手は経路に依存する結果として置換表に記録されるので、しきい値を無限大にすれば必ず詰みか不詰が確定する。
+    let result = mid_with_options_and_stats(
         dfpn_tbl,
         position,
-        (u32::MAX - 1, u32::MAX - 1),
+        (INF, INF),
         NodeKind::Or,
-        true,
         &mut Default::default(),
         verbose,
         stats,
         move_ordering,
+        leaf_init,
+        &SearchLimits::default(),
     );
-    // ループを見つけてしまった
-    if phi_now != u32::MAX && delta_now != u32::MAX {
-        eprintln!("! loop found: {} {}", phi_now, delta_now);
-        dfpn_tbl.clear();
-        return mid_with_options_and_stats(
-            dfpn_tbl,
-            position,
-            (u32::MAX, u32::MAX),
-            NodeKind::Or,
-            false,
-            &mut Default::default(),
-            verbose,
-            stats,
-            move_ordering,
-        );
-    }
-    (phi_now, delta_now)
+    stats.hashfull = dfpn_tbl.hashfull();
+    stats.tt = dfpn_tbl.stats();
+    result
+}
+
+/// 打ち切り条件 limits に達するまで探索する。
+pub fn df_pn_with_limits(
+    dfpn_tbl: &DfPnTable,
+    position: &PositionWrapper,
+    verbose: bool,
+    stats: &mut SearchStats,
+    move_ordering: &MoveOrderingOptions,
+    leaf_init: &LeafInitOptions,
+    limits: &SearchLimits,
+) -> Outcome {
+    let result = mid_with_options_and_stats(
+        dfpn_tbl,
+        position,
+        (INF, INF),
+        NodeKind::Or,
+        &mut Default::default(),
+        verbose,
+        stats,
+        move_ordering,
+        leaf_init,
+        limits,
+    );
+    stats.hashfull = dfpn_tbl.hashfull();
+    stats.tt = dfpn_tbl.stats();
+    result.into()
+}
+
+/// 自玉詰を解く。攻め方が王手の連続で、玉方に攻め方の玉を詰まさせられるかを調べる。
+///
+/// 打ち切り条件 limits に達するまで探索する。
+pub fn selfmate_with_limits(
+    dfpn_tbl: &DfPnTable,
+    position: &PositionWrapper,
+    verbose: bool,
+    stats: &mut SearchStats,
+    move_ordering: &MoveOrderingOptions,
+    leaf_init: &LeafInitOptions,
+    limits: &SearchLimits,
+) -> Outcome {
+    let result = mid_with_options_and_stats(
+        dfpn_tbl,
+        position,
+        (INF, INF),
+        NodeKind::SelfOr,
+        &mut Default::default(),
+        verbose,
+        stats,
+        move_ordering,
+        leaf_init,
+        limits,
+    );
+    stats.hashfull = dfpn_tbl.hashfull();
+    stats.tt = dfpn_tbl.stats();
+    result.into()
 }
 
-// ノードの展開
+// ノードの展開
 // (新しい phi(現在の局面), 新しい delta(現在の局面)) を返す。
 pub fn mid(
-    dfpn_tbl: &mut DfPnTable,
+    dfpn_tbl: &DfPnTable,
     position: &PositionWrapper,
     (phi_now, delta_now): (u32, u32),
     node_kind: NodeKind,
-    allow_loop: bool,
     ctx: &mut SearchCtx,
     verbose: bool,
 ) -> (u32, u32) {
@@ -145,22 +393,21 @@ pub fn mid(
         position,
         (phi_now, delta_now),
         node_kind,
-        allow_loop,
         ctx,
         verbose,
         &mut SearchStats::default(),
         &MoveOrderingOptions::default(),
+        &LeafInitOptions::default(),
+        &SearchLimits::default(),
     )
 }
 
 // Expands a node and returns updated proof/disproof numbers.
-#[allow(clippy::too_many_arguments)]
 pub fn mid_with_stats(
-    dfpn_tbl: &mut DfPnTable,
+    dfpn_tbl: &DfPnTable,
     position: &PositionWrapper,
     (phi_now, delta_now): (u32, u32),
     node_kind: NodeKind,
-    allow_loop: bool,
     ctx: &mut SearchCtx,
     verbose: bool,
     stats: &mut SearchStats,
@@ -170,38 +417,249 @@ pub fn mid_with_stats(
         position,
         (phi_now, delta_now),
         node_kind,
-        allow_loop,
         ctx,
         verbose,
         stats,
         &MoveOrderingOptions::default(),
+        &LeafInitOptions::default(),
+        &SearchLimits::default(),
     )
 }
 
+// 展開中のノードの子ノード。
+#[derive(Clone)]
+struct Child {
+    key: DfPnKey,
+    // 子ノードの局面のハッシュ値
+    hash: Key,
+    // 子ノードまでの経路のハッシュ値
+    path_key: Key,
+    // 子ノードが探索経路上の局面の繰り返しであれば、その局面の経路上での位置
+    repetition: Option<u32>,
+    // 置換表にないときの (phi, delta)
+    initial: (u32, u32),
+    // この経路で探索したときに返ってきた (phi, delta)
+    returned: Option<(u32, u32)>,
+}
+
+// 展開中のノード。バッファは探索の深さごとに使い回す。
+#[derive(Clone)]
+struct Frame {
+    position: PositionWrapper,
+    key: DfPnKey,
+    // ルートからこのノードまでの経路のハッシュ値
+    path_key: Key,
+    node_kind: NodeKind,
+    // 探索経路上での位置
+    depth: u32,
+    // (phi, delta) のしきい値
+    thresholds: (u32, u32),
+    // 最後に計算した (phi, delta)
+    current: (u32, u32),
+    moves: Vec<Move>,
+    children: Vec<Child>,
+    entries: Vec<DfPnEntry>,
+    depths: Vec<u32>,
+    // phi_sum で使う (source, phi) のバッファ
+    sources: Vec<(Key, u32)>,
+    // 最後に探索した子ノードの番号
+    selected: usize,
+    // 展開したときの探索ノード数
+    opened_at: u64,
+}
+
+impl Frame {
+    // 最後に探索した子ノードが value を返した。
+    fn returned(&mut self, value: (u32, u32)) {
+        self.children[self.selected].returned = Some(value);
+    }
+}
+
+// 子ノードのエントリーと、その結果が依存する経路上の局面の位置 (依存しなければ u32::MAX)。
+fn look_up_child(dfpn_tbl: &DfPnTable, child: &Child, node_kind: NodeKind) -> (DfPnEntry, u32) {
+    if let Some(index) = child.repetition {
+        // 千日手は攻め方の負け。
+        let (phi, delta) = if node_kind.flip().is_attacker() {
+            (INF, 0)
+        } else {
+            (0, INF)
+        };
+        let entry = DfPnEntry {
+            mine: child.key.mine,
+            yours: child.key.yours,
+            phi,
+            delta,
+            path: None,
+            source: None,
+        };
+        return (entry, index);
+    }
+    let mut entry = look_up_hash(dfpn_tbl, &child.key, child.path_key, child.initial);
+    let depth = entry.path.map_or(u32::MAX, |path| path.depth);
+    // 千日手を含む経路では、子ノードが返した値が置換表から引けないことがある
+    // (別の経路のエントリーが使われる、追い出される)。そのままだと同じ子ノードを同じしきい値で
+    // 探索し直し続けるので、確定していない値はこの経路で返ってきた値以上とし、しきい値を増やす (TCA)。
+    if let Some(value) = child.returned {
+        let unresolved = |(phi, delta): (u32, u32)| phi != 0 && delta != 0;
+        if unresolved(value) && unresolved(entry.value()) {
+            entry.phi = entry.phi.max(value.0);
+            entry.delta = entry.delta.max(value.1);
+        }
+    }
+    (entry, depth)
+}
+
 // Expands a node and returns updated proof/disproof numbers.
+// 再帰せずに、展開中のノードをスタックに積んで探索する。
+// 打ち切り条件に達したら、置換表を更新せずにその時点の値を返す。
 #[allow(clippy::too_many_arguments)]
 pub fn mid_with_options_and_stats(
-    dfpn_tbl: &mut DfPnTable,
+    dfpn_tbl: &DfPnTable,
     position: &PositionWrapper,
-    (mut phi_now, mut delta_now): (u32, u32),
+    (phi_now, delta_now): (u32, u32),
     node_kind: NodeKind,
-    allow_loop: bool,
     ctx: &mut SearchCtx,
     verbose: bool,
     stats: &mut SearchStats,
     move_ordering: &MoveOrderingOptions,
+    leaf_init: &LeafInitOptions,
+    limits: &SearchLimits,
 ) -> (u32, u32) {
-    stats.positions_inspected += 1;
-    if ctx.seq.len() >= 50 {
-        panic!();
+    let mut frames = core::mem::take(&mut ctx.frames);
+    let result = run(
+        dfpn_tbl,
+        &mut frames,
+        position,
+        (phi_now, delta_now),
+        node_kind,
+        ctx,
+        verbose,
+        stats,
+        move_ordering,
+        leaf_init,
+        limits,
+    );
+    ctx.frames = frames;
+    result
+}
+
+#[allow(clippy::too_many_arguments)]
+fn run(
+    dfpn_tbl: &DfPnTable,
+    frames: &mut Vec<Frame>,
+    position: &PositionWrapper,
+    thresholds: (u32, u32),
+    node_kind: NodeKind,
+    ctx: &mut SearchCtx,
+    verbose: bool,
+    stats: &mut SearchStats,
+    move_ordering: &MoveOrderingOptions,
+    leaf_init: &LeafInitOptions,
+    limits: &SearchLimits,
+) -> (u32, u32) {
+    ctx.stopped = false;
+    if let Err(value) = open_node(
+        dfpn_tbl,
+        frames,
+        0,
+        position.clone(),
+        thresholds,
+        node_kind,
+        ctx,
+        verbose,
+        stats,
+        move_ordering,
+        leaf_init,
+        limits,
+    ) {
+        return value;
+    }
+    // 展開中のノードの個数
+    let mut len = 1;
+    loop {
+        let index = len - 1;
+        match step(dfpn_tbl, &mut frames[index], ctx, verbose, stats) {
+            Step::Done(value) => {
+                len -= 1;
+                if len == 0 {
+                    return value;
+                }
+                frames[len - 1].returned(value);
+                ctx.pop();
+            }
+            Step::Descend(mv, thresholds) => {
+                let mut next = frames[index].position.clone();
+                next.make_move(mv);
+                ctx.push(mv);
+                let opened = open_node(
+                    dfpn_tbl,
+                    frames,
+                    len,
+                    next,
+                    thresholds,
+                    frames[index].node_kind.flip(),
+                    ctx,
+                    verbose,
+                    stats,
+                    move_ordering,
+                    leaf_init,
+                    limits,
+                );
+                match opened {
+                    Ok(()) => {
+                        len += 1;
+                        continue;
+                    }
+                    Err(value) => frames[index].returned(value),
+                }
+                ctx.pop();
+            }
+        }
+        if ctx.stopped {
+            // 展開中のノードをすべて閉じる。
+            for _ in 0..len {
+                ctx.path.pop();
+            }
+            for _ in 1..len {
+                ctx.pop();
+            }
+            return frames[0].current;
+        }
     }
-    let (phi, delta) = look_up_hash(dfpn_tbl, position.zobrist_hash());
+}
+
+// frames[index] に position のノードを展開する。
+// 展開せずに値が決まった場合は Err にその値を入れて返す。
+#[allow(clippy::too_many_arguments)]
+fn open_node(
+    dfpn_tbl: &DfPnTable,
+    frames: &mut Vec<Frame>,
+    index: usize,
+    position: PositionWrapper,
+    (phi_now, delta_now): (u32, u32),
+    node_kind: NodeKind,
+    ctx: &mut SearchCtx,
+    verbose: bool,
+    stats: &mut SearchStats,
+    move_ordering: &MoveOrderingOptions,
+    leaf_init: &LeafInitOptions,
+    limits: &SearchLimits,
+) -> Result<(), (u32, u32)> {
+    stats.positions_inspected += 1;
+    limits.record_node();
+    let key = node_kind.key(&position);
+    let path_key = ctx.path_key_after(position.zobrist_hash());
+    let (phi, delta) = look_up_hash(dfpn_tbl, &key, path_key, (1, 1)).value();
     if verbose && (phi_now <= phi || delta_now <= delta) {
         eprintln!(
             "cut  : {:?} {} {} (hash = {} {})",
             ctx, phi_now, delta_now, phi, delta
         );
-        return (phi, delta);
+        return Err((phi, delta));
+    }
+    if limits.is_reached(stats) {
+        ctx.stopped = true;
+        return Err((phi, delta));
     }
     if verbose && ctx.seq.len() <= 3 {
         eprintln!(
@@ -214,167 +672,407 @@ pub fn mid_with_options_and_stats(
             delta
         );
     }
-    let mut moves = match node_kind {
-        NodeKind::Or => position.all_checks(),
-        NodeKind::And => position.all_evasions(),
+    let mut moves = match frames.get_mut(index) {
+        Some(frame) => core::mem::take(&mut frame.moves),
+        None => Vec::new(),
     };
-    if moves.is_empty() {
-        put_in_hash(dfpn_tbl, position.zobrist_hash(), (u32::MAX, 0));
-        return (u32::MAX, 0);
+    moves.clear();
+    // 自玉詰の攻め方は、王手をかけられていて応手がなければ自分の玉が詰んでいるので勝ち。
+    let self_mated = matches!(node_kind, NodeKind::SelfOr)
+        && is_in_check_partial_lite(position.inner())
+        && position.all_evasions().is_empty();
+    match node_kind {
+        NodeKind::Or | NodeKind::SelfOr => moves.extend(position.all_checks()),
+        NodeKind::And | NodeKind::SelfAnd => moves.extend(position.all_evasions()),
     }
-    order_df_pn_moves(&mut moves, move_ordering);
-    let mut children = vec![];
-    for mv in moves {
-        let mut cp = position.clone();
-        cp.make_move(mv);
-        children.push((mv, cp.zobrist_hash()));
-    }
-    // 3. ハッシュによるサイクル回避
-    if allow_loop {
-        put_in_hash(dfpn_tbl, position.zobrist_hash(), (phi_now, delta_now));
-    } else {
-        // どちらの手番でも不詰とする。
+    if moves.is_empty() {
+        // 自玉詰の玉方は、応手がなければ自分の玉が詰まされたので勝ち。
         let value = match node_kind {
-            NodeKind::Or => (u32::MAX, 0),
-            NodeKind::And => (0, u32::MAX),
+            NodeKind::SelfOr if self_mated => (0, INF),
+            NodeKind::SelfAnd => (0, INF),
+            _ => (INF, 0),
+        };
+        let (mine, yours) = if node_kind.is_selfmate() {
+            (key.mine, key.yours)
+        } else {
+            loss_hands(&position, &[], &[])
         };
-        put_in_hash(dfpn_tbl, position.zobrist_hash(), value);
+        put_in_hash(dfpn_tbl, &DfPnKey { mine, yours, ..key }, value);
+        if let Some(frame) = frames.get_mut(index) {
+            frame.moves = moves;
+        }
+        return Err(value);
     }
-
-    // 4. 多重反復深化
-    loop {
-        let phi_sum = phi_sum(dfpn_tbl, &children);
-        let delta_min = delta_min(dfpn_tbl, &children);
-
-        // φ か δ がそのしきい値以上なら探索終了
-        if phi_now <= delta_min || delta_now <= phi_sum {
-            phi_now = delta_min;
-            delta_now = phi_sum;
-            put_in_hash(dfpn_tbl, position.zobrist_hash(), (phi_now, delta_now));
-            if verbose && ctx.seq.len() <= 3 {
-                eprintln!(
-                    "end  : {:?} {:016x} hash = {} {}",
-                    ctx,
-                    position.zobrist_hash(),
-                    phi_now,
-                    delta_now
-                );
+    order_df_pn_moves(&mut moves, move_ordering);
+    // 1 手詰めがあれば子ノードを展開せずに証明する。
+    if let NodeKind::Or = node_kind {
+        if let Some(mv) = position.mate_in_one_among(&moves) {
+            let mut child = position.clone();
+            child.make_move(mv);
+            let (mine, yours) = loss_hands(&child, &[], &[]);
+            let child_entry = DfPnEntry {
+                mine,
+                yours,
+                phi: INF,
+                delta: 0,
+                path: None,
+                source: None,
+            };
+            let (mine, yours) = win_hands(&position, mv, &child_entry);
+            put_in_hash(dfpn_tbl, &DfPnKey { mine, yours, ..key }, (0, INF));
+            if let Some(frame) = frames.get_mut(index) {
+                frame.moves = moves;
+            }
+            return Err((0, INF));
+        }
+    }
+    // 1 手で自玉詰にできれば、同じように子ノードを展開せずに証明する。
+    if let NodeKind::SelfOr = node_kind {
+        if position.selfmate_in_one_among(&moves).is_some() {
+            put_in_hash(dfpn_tbl, &key, (0, INF));
+            if let Some(frame) = frames.get_mut(index) {
+                frame.moves = moves;
             }
-            return (phi_now, delta_now);
+            return Err((0, INF));
         }
-        let ((mv, _n_c, phi_c, delta_c), delta_2) = select_child(dfpn_tbl, &children);
+    }
+    // 3. 探索経路上の局面との繰り返しの検出
+    let depth = ctx.path.len() as u32;
+    ctx.path.push((position.zobrist_hash(), path_key));
+    if index == frames.len() {
+        frames.push(Frame {
+            position: position.clone(),
+            key,
+            path_key,
+            node_kind,
+            depth,
+            thresholds: (phi_now, delta_now),
+            current: (phi, delta),
+            moves: Vec::new(),
+            children: Vec::new(),
+            entries: Vec::new(),
+            depths: Vec::new(),
+            sources: Vec::new(),
+            selected: 0,
+            opened_at: 0,
+        });
+    }
+    let frame = &mut frames[index];
+    frame.children.clear();
+    // 子ノードは 1 つの局面で指しては戻して調べる。
+    let mut cp = position.clone();
+    for &mv in &moves {
+        let undo = cp.make_move(mv);
+        let child_key = node_kind.flip().key(&cp);
+        // 子ノードの置換表を引く前に、バケットを読み込んでおく。
+        dfpn_tbl.prefetch(&child_key);
+        frame.children.push(Child {
+            key: child_key,
+            hash: cp.zobrist_hash(),
+            path_key: ctx.path_key_after(cp.zobrist_hash()),
+            repetition: ctx.repetition(cp.zobrist_hash()),
+            initial: leaf_init::initial_value(&position, mv, &cp, node_kind.flip(), leaf_init),
+            returned: None,
+        });
+        cp.unmake_move(undo);
+    }
+    frame.position = position;
+    frame.key = key;
+    frame.path_key = path_key;
+    frame.node_kind = node_kind;
+    frame.depth = depth;
+    frame.thresholds = (phi_now, delta_now);
+    frame.current = (phi, delta);
+    frame.moves = moves;
+    frame.opened_at = stats.positions_inspected;
+    Ok(())
+}
+
+// 展開中のノードに対する 1 回の反復の結果。
+enum Step {
+    // 探索を終えた。置換表に記録した (phi, delta) を持つ。
+    Done((u32, u32)),
+    // この手で進めた子ノードを、このしきい値で探索する。
+    Descend(Move, (u32, u32)),
+}
+
+// 4. 多重反復深化の 1 回分
+fn step(
+    dfpn_tbl: &DfPnTable,
+    frame: &mut Frame,
+    ctx: &mut SearchCtx,
+    verbose: bool,
+    stats: &SearchStats,
+) -> Step {
+    let Frame {
+        position,
+        key,
+        path_key,
+        node_kind,
+        depth,
+        thresholds: (phi_now, delta_now),
+        current,
+        moves,
+        children,
+        entries,
+        depths,
+        sources,
+        selected,
+        opened_at,
+    } = frame;
+    let (phi_now, delta_now) = (*phi_now, *delta_now);
+    entries.clear();
+    depths.clear();
+    for child in children.iter() {
+        let (entry, depth) = look_up_child(dfpn_tbl, child, *node_kind);
+        entries.push(entry);
+        depths.push(depth);
+    }
+    let phi_sum = phi_sum(entries, sources);
+    let delta_min = delta_min(entries);
+    *current = (delta_min, phi_sum);
 
-        let phi_n_c = if phi_c == u32::MAX - 1 {
-            u32::MAX
-        } else if delta_now >= u32::MAX - 1 {
-            u32::MAX - 1
+    // φ か δ がそのしきい値以上なら探索終了
+    if phi_now <= delta_min || delta_now <= phi_sum {
+        let (phi_now, delta_now) = (delta_min, phi_sum);
+        // 結果が依存する探索経路上の局面の位置
+        let mut dependency = u32::MAX;
+        let (mine, yours) = if phi_now == 0 {
+            // 勝ちを決めた子ノードの証明駒から計算する。
+            // 経路に依存しない子ノードがあればそれを優先する。
+            let index = (0..children.len())
+                .filter(|&i| entries[i].is_loss())
+                .max_by_key(|&i| depths[i])
+                .unwrap();
+            dependency = depths[index];
+            win_hands(position, moves[index], &entries[index])
+        } else if delta_now == 0 {
+            dependency = depths.iter().copied().min().unwrap_or(u32::MAX);
+            loss_hands(position, moves, entries)
         } else {
-            delta_now + phi_c - phi_sum
+            (key.mine, key.yours)
         };
-        let delta_n_c = if delta_c == u32::MAX - 1 {
-            u32::MAX
+        // 自玉詰では証明駒・反証駒を使わず、持ち駒がちょうど同じ局面にだけ結果を使う。
+        let (mine, yours) = if node_kind.is_selfmate() {
+            (key.mine, key.yours)
         } else {
-            core::cmp::min(phi_now, delta_2.saturating_add(1))
+            (mine, yours)
         };
-        let mut next = position.clone();
-        next.make_move(mv);
-        ctx.push(mv);
-        mid_with_options_and_stats(
-            dfpn_tbl,
-            &next,
-            (phi_n_c, delta_n_c),
-            node_kind.flip(),
-            allow_loop,
-            ctx,
-            verbose,
-            stats,
-            move_ordering,
-        );
-        ctx.pop();
+        // 確定していなければ、phi を受け継いだ子ノードを記録する。
+        let source = if phi_now != 0 && delta_now != 0 {
+            let index = entries.iter().position(|entry| entry.delta == phi_now);
+            index.map(|index| children[index].hash)
+        } else {
+            None
+        };
+        // このノードを展開してから探索したノード数
+        let effort = (stats.positions_inspected - *opened_at + 1).min(u32::MAX as u64) as u32;
+        if dependency < *depth {
+            // 現在の局面より上の局面との千日手に依存するので、この経路でしか使えない。
+            dfpn_tbl.insert_on_path(
+                key,
+                (phi_now, delta_now),
+                PathDependency {
+                    path_key: *path_key,
+                    depth: dependency,
+                },
+                effort,
+            );
+        } else {
+            dfpn_tbl.insert_with_source(
+                &DfPnKey {
+                    mine,
+                    yours,
+                    ..*key
+                },
+                (phi_now, delta_now),
+                source,
+                effort,
+            );
+        }
+        ctx.path.pop();
+        if verbose && ctx.seq.len() <= 3 {
+            eprintln!(
+                "end  : {:?} {:016x} hash = {} {}",
+                ctx,
+                position.zobrist_hash(),
+                phi_now,
+                delta_now
+            );
+        }
+        return Step::Done((phi_now, delta_now));
     }
+    let ((index, phi_c, _), delta_2) = select_child(entries, ctx.rng.as_mut());
+
+    let phi_n_c = if delta_now == INF {
+        INF
+    } else {
+        delta_now - (phi_sum - phi_c)
+    };
+    let delta_n_c = core::cmp::min(phi_now, delta_2.saturating_add(1));
+    *selected = index;
+    Step::Descend(moves[index], (phi_n_c, delta_n_c))
 }
 
-// 子ノードの選択
-// ((子ノードへ向かうための手, 子ノードのハッシュ, phi_c, delta_c), delta_2) を返す。
-fn select_child(dfpn_tbl: &DfPnTable, children: &[(Move, Key)]) -> ((Move, Key, u32, u32), u32) {
-    debug_assert!(!children.is_empty());
-    let mut n_best = (
-        Move::Normal {
-            from: Square::SQ_1A,
-            to: Square::SQ_1A,
-            promote: false,
-        },
-        0,
-    );
-    let mut phi_c = u32::MAX;
-    let mut delta_c = u32::MAX;
-    let mut delta_2 = u32::MAX;
-    for &(mv, hash) in children {
-        let (phi, delta) = look_up_hash(dfpn_tbl, hash);
+// 子ノードの選択
+// ((子ノードの番号, phi_c, delta_c), delta_2) を返す。
+// rng があれば、delta が最小の子ノードが複数あるときにその中から一様に選ぶ。
+fn select_child(
+    entries: &[DfPnEntry],
+    mut rng: Option<&mut ChaCha8Rng>,
+) -> ((usize, u32, u32), u32) {
+    debug_assert!(!entries.is_empty());
+    let mut n_best = 0;
+    let mut phi_c = INF;
+    let mut delta_c = INF;
+    let mut delta_2 = INF;
+    // delta が delta_c に等しい子ノードの個数
+    let mut ties = 0;
+    for (index, entry) in entries.iter().enumerate() {
+        let (phi, delta) = entry.value();
         if delta < delta_c {
-            n_best = (mv, hash);
+            n_best = index;
             delta_2 = delta_c;
             phi_c = phi;
             delta_c = delta;
+            ties = 1;
+        } else if delta == delta_c && rng.is_some() {
+            delta_2 = delta;
+            ties += 1;
+            if rng.as_mut().unwrap().random_range(0..ties) == 0 {
+                n_best = index;
+                phi_c = phi;
+            }
         } else if delta < delta_2 {
             delta_2 = delta;
         }
-        if phi == u32::MAX {
-            return ((n_best.0, n_best.1, phi_c, delta_c), delta_2);
+        if phi == INF {
+            return ((n_best, phi_c, delta_c), delta_2);
         }
     }
-    ((n_best.0, n_best.1, phi_c, delta_c), delta_2)
+    ((n_best, phi_c, delta_c), delta_2)
 }
 
-// ハッシュを引く (本当は優越関係が使える)
-fn look_up_hash(dfpn_tbl: &DfPnTable, position: Key) -> (u32, u32) {
-    if let Some(x) = dfpn_tbl.fetch(position) {
+// ハッシュを引く。持ち駒の優越関係を使って、証明済み・反証済みの局面の結果も流用する。
+// 経路に依存する結果は、経路のハッシュ値 path_key が一致するときだけ使う。
+// 見つからなければ (phi, delta) = initial とする。
+fn look_up_hash(
+    dfpn_tbl: &DfPnTable,
+    key: &DfPnKey,
+    path_key: Key,
+    (phi, delta): (u32, u32),
+) -> DfPnEntry {
+    if let Some(x) = dfpn_tbl.fetch_on_path(key, path_key) {
         return x;
     }
-    (1, 1)
+    DfPnEntry {
+        mine: key.mine,
+        yours: key.yours,
+        phi,
+        delta,
+        path: None,
+        source: None,
+    }
 }
 
 // ハッシュに記録
-fn put_in_hash(dfpn_tbl: &mut DfPnTable, position: Key, (phi, delta): (u32, u32)) {
-    dfpn_tbl.insert(position, (phi, delta));
+fn put_in_hash(dfpn_tbl: &DfPnTable, key: &DfPnKey, (phi, delta): (u32, u32)) {
+    dfpn_tbl.insert(key, (phi, delta));
 }
 
-// n の子ノード の δ の最小を計算
-fn delta_min(dfpn_tbl: &DfPnTable, children: &[(Move, Key)]) -> u32 {
+// n の子ノード の δ の最小を計算
+fn delta_min(entries: &[DfPnEntry]) -> u32 {
     let mut mi = u32::MAX;
-    for &child in children {
-        let (_, delta) = look_up_hash(dfpn_tbl, child.1);
-        mi = core::cmp::min(mi, delta);
+    for entry in entries {
+        mi = core::cmp::min(mi, entry.delta);
     }
     mi
 }
 
-// nの子ノードのφの和を計算
-fn phi_sum(dfpn_tbl: &DfPnTable, children: &[(Move, Key)]) -> u32 {
+// nの子ノードのφの和を計算
+// 確定していない値の和は INF - 1 で打ち切る。
+// 同じ局面 (source) から phi を受け継いだ子ノードが複数あれば、合流した先の値を二重に数えないように
+// その中の最大値だけを足す (SNDA)。sources は作業用のバッファ。
+fn phi_sum(entries: &[DfPnEntry], sources: &mut Vec<(Key, u32)>) -> u32 {
     let mut sum: u32 = 0;
-    let mut infm1 = false;
-    for &child in children {
-        let (phi, _) = look_up_hash(dfpn_tbl, child.1);
-        if phi == u32::MAX {
-            return u32::MAX;
+    sources.clear();
+    for entry in entries {
+        let phi = entry.phi;
+        if phi == INF {
+            return INF;
         }
-        if phi == u32::MAX - 1 {
-            infm1 = true;
-        } else {
+        match entry.source {
+            Some(source) if phi != 0 => sources.push((source, phi)),
+            _ => sum = sum.saturating_add(phi),
+        }
+    }
+    sources.sort_unstable();
+    for (i, &(source, phi)) in sources.iter().enumerate() {
+        // 同じ source の中で最大のもの (ソート後の最後) だけを足す。
+        if sources.get(i + 1).is_none_or(|&(next, _)| next != source) {
             sum = sum.saturating_add(phi);
         }
     }
-    if infm1 {
-        u32::MAX - 1
-    } else {
-        sum
+    core::cmp::min(sum, INF - 1)
+}
+
+// 手番側が mv を指したときの持ち駒の変化 (打った駒, 取った駒)。
+fn hand_change(position: &PositionWrapper, mv: Move) -> (Option<PieceKind>, Option<PieceKind>) {
+    match mv {
+        Move::Normal { to, .. } => {
+            let captured = position.inner().piece_at(to).map(|piece| {
+                let piece_kind = piece.piece_kind();
+                piece_kind.unpromote().unwrap_or(piece_kind)
+            });
+            (None, captured)
+        }
+        Move::Drop { piece, .. } => (Some(piece.piece_kind()), None),
+    }
+}
+
+// 子ノードでの手番側の持ち駒の限度から、mv を指す前の持ち駒の限度を求める。
+fn hand_before(position: &PositionWrapper, mv: Move, after: Hand) -> Hand {
+    match hand_change(position, mv) {
+        (Some(dropped), _) => hand::added_saturating(after, dropped),
+        (_, Some(captured)) => hand::removed_saturating(after, captured),
+        (None, None) => after,
+    }
+}
+
+// 手番側の勝ちが確定した局面の証明駒 (手番側の持ち駒の下限, 相手側の持ち駒の上限) を計算する。
+// child は mv を指した後の局面のエントリーで、手番側 (= こちらの相手) の負けが確定している。
+fn win_hands(position: &PositionWrapper, mv: Move, child: &DfPnEntry) -> (Hand, Hand) {
+    debug_assert!(child.is_loss());
+    (hand_before(position, mv, child.yours), child.mine)
+}
+
+// 手番側の負けが確定した局面の反証駒 (手番側の持ち駒の上限, 相手側の持ち駒の下限) を計算する。
+// entries は各子ノードのエントリーで、すべて手番側 (= こちらの相手) の勝ちが確定している。
+fn loss_hands(position: &PositionWrapper, moves: &[Move], entries: &[DfPnEntry]) -> (Hand, Hand) {
+    let key = position.df_pn_key();
+    // 持っていない種類の駒が増えると新しく駒打ちができるようになるので、増やしてはならない。
+    // 持っている種類の駒は、何枚増えても打てる場所は変わらない。
+    let actual = hand::counts(key.mine);
+    let mut mine = hand::from_counts(core::array::from_fn(|i| {
+        if actual[i] == 0 {
+            0
+        } else {
+            actual[i].max(hand::MAX_COUNTS[i])
+        }
+    }));
+    let mut yours = Hand::new();
+    for (&mv, entry) in moves.iter().zip(entries) {
+        debug_assert!(entry.is_win());
+        mine = hand::min(mine, hand_before(position, mv, entry.yours));
+        yours = hand::max(yours, entry.mine);
     }
+    (mine, yours)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use shogi_core::PartialPosition;
+    use shogi_core::{PartialPosition, Square};
 
     #[test]
     fn solve_mate_problem_works_0() {
@@ -386,8 +1084,8 @@ mod tests {
                 .unwrap();
         let wrapped = PositionWrapper::new(position);
 
-        let mut dfpn_tbl = DfPnTable::new(1 << 15);
-        let result = df_pn(&mut dfpn_tbl, &wrapped, false);
+        let dfpn_tbl = DfPnTable::new(1 << 15);
+        let result = df_pn(&dfpn_tbl, &wrapped, false);
         // 詰み
         assert_eq!(result, (0, u32::MAX));
 
@@ -408,7 +1106,7 @@ mod tests {
         for mv in moves {
             tmp.make_move(mv);
         }
-        let result = df_pn(&mut dfpn_tbl, &tmp, false);
+        let result = df_pn(&dfpn_tbl, &tmp, false);
         // 不詰
         assert_eq!(result, (u32::MAX, 0));
 
@@ -429,7 +1127,7 @@ mod tests {
         for mv in moves {
             tmp.make_move(mv);
         }
-        let result = df_pn(&mut dfpn_tbl, &tmp, false);
+        let result = df_pn(&dfpn_tbl, &tmp, false);
         // 不詰
         assert_eq!(result, (u32::MAX, 0));
     }
@@ -444,8 +1142,8 @@ mod tests {
                 .unwrap();
         let wrapped = PositionWrapper::new(position);
 
-        let mut dfpn_tbl = DfPnTable::new(1 << 20);
-        let result = df_pn(&mut dfpn_tbl, &wrapped, false);
+        let dfpn_tbl = DfPnTable::new(1 << 20);
+        let result = df_pn(&dfpn_tbl, &wrapped, false);
         // 詰み
         assert_eq!(result, (0, u32::MAX));
     }
@@ -460,8 +1158,8 @@ mod tests {
                 .unwrap();
         let wrapped = PositionWrapper::new(position);
 
-        let mut dfpn_tbl = DfPnTable::new(1 << 20);
-        let result = df_pn(&mut dfpn_tbl, &wrapped, false);
+        let dfpn_tbl = DfPnTable::new(1 << 20);
+        let result = df_pn(&dfpn_tbl, &wrapped, false);
         // 詰み
         assert_eq!(result, (0, u32::MAX));
     }
@@ -473,8 +1171,8 @@ mod tests {
         let position = PartialPosition::from_usi("sfen 7kl/9/6G1p/9/9/9/9/9/9 b S 1").unwrap();
         let wrapped = PositionWrapper::new(position);
 
-        let mut dfpn_tbl = DfPnTable::new(1 << 20);
-        let result = df_pn(&mut dfpn_tbl, &wrapped, false);
+        let dfpn_tbl = DfPnTable::new(1 << 20);
+        let result = df_pn(&dfpn_tbl, &wrapped, false);
         // 詰み
         assert_eq!(result, (0, u32::MAX));
     }
@@ -487,9 +1185,305 @@ mod tests {
             PartialPosition::from_usi("sfen 8k/9/9/9/9/9/9/9/9 b Rr2b4g4s4n4l18p 1").unwrap();
         let wrapped = PositionWrapper::new(position);
 
-        let mut dfpn_tbl = DfPnTable::new(1 << 20);
-        let result = df_pn(&mut dfpn_tbl, &wrapped, false);
+        let dfpn_tbl = DfPnTable::new(1 << 20);
+        let result = df_pn(&dfpn_tbl, &wrapped, false);
         // 不詰
         assert_eq!(result, (u32::MAX, 0));
     }
+
+    #[test]
+    fn proof_is_shared_with_superior_hands() {
+        use shogi_usi_parser::FromUsi;
+
+        let position =
+            PartialPosition::from_usi("sfen 3g1ks2/6g2/4S4/7B1/9/9/9/9/9 b G2rbg2s4n4l18p 1")
+                .unwrap();
+        let dfpn_tbl = DfPnTable::new(1 << 15);
+        let result = df_pn(&dfpn_tbl, &PositionWrapper::new(position), false);
+        assert_eq!(result, (0, u32::MAX));
+
+        // 攻め方の持ち駒が多く、玉方の持ち駒が少ない局面は探索しなくても詰みと分かる。
+        let superior =
+            PartialPosition::from_usi("sfen 3g1ks2/6g2/4S4/7B1/9/9/9/9/9 b GS2rbgs4n4l18p 1")
+                .unwrap();
+        let entry = dfpn_tbl
+            .fetch(&PositionWrapper::new(superior).df_pn_key())
+            .unwrap();
+        assert_eq!(entry.value(), (0, u32::MAX));
+    }
+
+    #[test]
+    fn perpetual_check_is_disproved_without_retry() {
+        use shogi_usi_parser::FromUsi;
+
+        // 飛車の王手は千日手にしかならない。
+        let position =
+            PartialPosition::from_usi("sfen 8k/9/9/9/9/9/9/9/9 b Rr2b4g4s4n4l18p 1").unwrap();
+        let wrapped = PositionWrapper::new(position);
+
+        let dfpn_tbl = DfPnTable::new(1 << 20);
+        let mut ctx = SearchCtx::default();
+        let result = mid(
+            &dfpn_tbl,
+            &wrapped,
+            (INF, INF),
+            NodeKind::Or,
+            &mut ctx,
+            false,
+        );
+        assert_eq!(result, (INF, 0));
+        assert!(ctx.path.is_empty());
+        // ルートの不詰は経路に依存しない。
+        let entry = dfpn_tbl.fetch(&wrapped.df_pn_key()).unwrap();
+        assert_eq!(entry.value(), (INF, 0));
+    }
+
+    #[test]
+    fn repetition_chain_terminates_with_small_table() {
+        use shogi_usi_parser::FromUsi;
+
+        // 飛車の王手は千日手にしかならない。置換表が小さいと子ノードの値がすぐに追い出されるが、
+        // 同じ子ノードの間を行き来し続けずに不詰を示せる。
+        let position =
+            PartialPosition::from_usi("sfen 8k/9/9/9/9/9/9/9/9 b Rr2b4g4s4n4l18p 1").unwrap();
+        let dfpn_tbl = DfPnTable::new(1 << 10);
+        let limits = SearchLimits {
+            max_nodes: Some(200_000),
+            ..Default::default()
+        };
+        let result = df_pn_with_limits(
+            &dfpn_tbl,
+            &PositionWrapper::new(position),
+            false,
+            &mut SearchStats::default(),
+            &MoveOrderingOptions::default(),
+            &LeafInitOptions::default(),
+            &limits,
+        );
+        assert_eq!(result, Outcome::NoMate);
+    }
+
+    #[test]
+    fn hand_variants_do_not_thrash_buckets() {
+        use shogi_usi_parser::FromUsi;
+
+        // 同じ盤面に持ち駒違いのエントリーが多くできる。置換表が十分に大きければ、
+        // 同じバケットに集まっても追い出し合わずに済み、調べる局面も増えない。
+        let position =
+            PartialPosition::from_usi("sfen 8k/9/9/9/9/9/9/9/9 b Rr2b4g4s4n4l18p 1").unwrap();
+        let dfpn_tbl = DfPnTable::with_megabytes(16);
+        let mut stats = SearchStats::default();
+        let result = df_pn_with_limits(
+            &dfpn_tbl,
+            &PositionWrapper::new(position),
+            false,
+            &mut stats,
+            &MoveOrderingOptions::default(),
+            &LeafInitOptions::default(),
+            &SearchLimits::default(),
+        );
+        assert_eq!(result, Outcome::NoMate);
+        assert!(stats.tt.bucket_full <= 50, "{:?}", stats.tt);
+        assert!(
+            stats.positions_inspected <= 1600,
+            "{}",
+            stats.positions_inspected
+        );
+    }
+
+    #[test]
+    fn select_child_breaks_ties_with_rng() {
+        let entry = |phi, delta| DfPnEntry {
+            mine: Hand::new(),
+            yours: Hand::new(),
+            phi,
+            delta,
+            path: None,
+            source: None,
+        };
+        let entries = [entry(3, 2), entry(1, 1), entry(2, 1), entry(4, 1)];
+        assert_eq!(select_child(&entries, None), ((1, 1, 1), 1));
+        let mut rng = ChaCha8Rng::seed_from_u64(1);
+        let mut chosen = [false; 4];
+        for _ in 0..64 {
+            let ((index, phi_c, delta_c), delta_2) = select_child(&entries, Some(&mut rng));
+            assert_eq!((phi_c, delta_c, delta_2), (entries[index].phi, 1, 1));
+            chosen[index] = true;
+        }
+        assert_eq!(chosen, [false, true, true, true]);
+    }
+
+    #[test]
+    fn search_stops_at_limits() {
+        use shogi_usi_parser::FromUsi;
+
+        let position =
+            PartialPosition::from_usi("sfen 5kgnl/9/4+B1pp1/8p/9/9/9/9/9 b 2S2rb3g2s3n3l15p 1")
+                .unwrap();
+        let wrapped = PositionWrapper::new(position);
+        let run = |limits: &SearchLimits| {
+            let dfpn_tbl = DfPnTable::new(1 << 16);
+            let mut stats = SearchStats::default();
+            let outcome = df_pn_with_limits(
+                &dfpn_tbl,
+                &wrapped,
+                false,
+                &mut stats,
+                &MoveOrderingOptions::default(),
+                &LeafInitOptions::default(),
+                limits,
+            );
+            (outcome, stats.positions_inspected)
+        };
+
+        let (outcome, nodes) = run(&SearchLimits {
+            max_nodes: Some(10),
+            ..Default::default()
+        });
+        assert!(matches!(outcome, Outcome::Unknown(_)));
+        assert!(nodes <= 11);
+
+        let cancel = Arc::new(AtomicBool::new(true));
+        let (outcome, _) = run(&SearchLimits {
+            cancel: Some(cancel),
+            ..Default::default()
+        });
+        assert!(matches!(outcome, Outcome::Unknown(_)));
+
+        let (outcome, _) = run(&SearchLimits {
+            deadline: Some(Instant::now()),
+            ..Default::default()
+        });
+        assert!(matches!(outcome, Outcome::Unknown(_)));
+
+        let (outcome, _) = run(&SearchLimits::default());
+        assert_eq!(outcome, Outcome::Mate);
+    }
+
+    #[test]
+    fn deadline_is_checked_at_intervals() {
+        let limits = SearchLimits {
+            deadline: Some(Instant::now()),
+            ..Default::default()
+        };
+        assert!(limits.is_reached_at(1));
+        assert!(!limits.is_reached_at(2));
+        assert!(!limits.is_reached_at(DEADLINE_CHECK_INTERVAL));
+        assert!(limits.is_reached_at(DEADLINE_CHECK_INTERVAL + 1));
+    }
+
+    #[test]
+    fn search_reuses_frames_across_calls() {
+        use shogi_usi_parser::FromUsi;
+
+        let position =
+            PartialPosition::from_usi("sfen 5kgnl/9/4+B1pp1/8p/9/9/9/9/9 b 2S2rb3g2s3n3l15p 1")
+                .unwrap();
+        let wrapped = PositionWrapper::new(position);
+        let mut ctx = SearchCtx::default();
+        let solve = |ctx: &mut SearchCtx| {
+            let dfpn_tbl = DfPnTable::new(1 << 16);
+            let mut stats = SearchStats::default();
+            let result = mid_with_stats(
+                &dfpn_tbl,
+                &wrapped,
+                (INF, INF),
+                NodeKind::Or,
+                ctx,
+                false,
+                &mut stats,
+            );
+            (result, stats.positions_inspected)
+        };
+
+        let first = solve(&mut ctx);
+        assert_eq!(first.0, (0, INF));
+        // 探索を終えると経路は空に戻り、展開したノードのバッファだけが残る。
+        assert!(ctx.seq.is_empty());
+        assert!(ctx.path.is_empty());
+        assert!(ctx.frames.len() >= 9);
+        let depth = ctx.frames.len();
+
+        assert_eq!(solve(&mut ctx), first);
+        assert_eq!(ctx.frames.len(), depth);
+    }
+
+    #[test]
+    fn leaf_init_policies_give_same_results() {
+        use crate::leaf_init::LeafInitMode;
+        use shogi_usi_parser::FromUsi;
+
+        let cases = [
+            ("3g1ks2/6g2/4S4/7B1/9/9/9/9/9 b G2rbg2s4n4l18p 1", (0, INF)),
+            ("7nk/9/6PB1/6NP1/9/9/9/9/9 b P2rb4g4s2n4l15p 1", (0, INF)),
+            ("8k/9/9/9/9/9/9/9/9 b Rr2b4g4s4n4l18p 1", (INF, 0)),
+        ];
+        for (sfen, expected) in cases {
+            let position = PartialPosition::from_usi(&format!("sfen {}", sfen)).unwrap();
+            let result = df_pn_with_options_and_stats(
+                &DfPnTable::new(1 << 16),
+                &PositionWrapper::new(position),
+                false,
+                &mut SearchStats::default(),
+                &MoveOrderingOptions::default(),
+                &LeafInitOptions {
+                    mode: LeafInitMode::Mobility,
+                },
+            );
+            assert_eq!(result, expected, "{}", sfen);
+        }
+    }
+
+    #[test]
+    fn mate_in_one_is_proved_without_expansion() {
+        use shogi_usi_parser::FromUsi;
+
+        let position =
+            PartialPosition::from_usi("sfen 8k/9/8P/9/9/9/9/9/9 b G2r2b3g4s4n4l17p 1").unwrap();
+        let wrapped = PositionWrapper::new(position);
+        let dfpn_tbl = DfPnTable::new(1 << 16);
+        let mut stats = SearchStats::default();
+        let result = df_pn_with_stats(&dfpn_tbl, &wrapped, false, &mut stats);
+        assert_eq!(result, (0, INF));
+        assert_eq!(stats.positions_inspected, 1);
+        // 証明駒は金 1 枚
+        let entry = dfpn_tbl.fetch(&wrapped.df_pn_key()).unwrap();
+        assert_eq!(entry.mine, hand::from_counts([0, 0, 0, 0, 1, 0, 0]));
+    }
+
+    #[test]
+    fn shared_source_is_counted_once() {
+        use shogi_usi_parser::FromUsi;
+
+        let position =
+            PartialPosition::from_usi("sfen 8k/9/9/9/9/9/9/9/9 b G2r2b3g4s4n4l18p 1").unwrap();
+        let wrapped = PositionWrapper::new(position);
+        let checks = wrapped.all_checks();
+        assert!(checks.len() >= 3);
+        let dfpn_tbl = DfPnTable::new(1 << 16);
+        // 最初の 2 つの王手の後の局面は、どちらも同じ局面から phi = 5 を受け継いでいる。
+        let source = 0x1234_5678;
+        for (i, &mv) in checks.iter().enumerate() {
+            let mut child = wrapped.clone();
+            child.make_move(mv);
+            let (phi, source) = if i < 2 { (5, Some(source)) } else { (1, None) };
+            dfpn_tbl.insert_with_source(&child.df_pn_key(), (phi, 1), source, 1);
+        }
+        let result = mid(
+            &dfpn_tbl,
+            &wrapped,
+            (1, INF),
+            NodeKind::Or,
+            &mut SearchCtx::default(),
+            false,
+        );
+        // 素朴に足すと 5 + 5 + (残りの王手の個数) になる。
+        assert_eq!(result, (1, 5 + checks.len() as u32 - 2));
+
+        // 最初の王手の後の局面から phi を受け継いだことが記録される。
+        let mut first = wrapped.clone();
+        first.make_move(checks[0]);
+        let entry = dfpn_tbl.fetch(&wrapped.df_pn_key()).unwrap();
+        assert_eq!(entry.source, Some(first.zobrist_hash()));
+    }
 }
@@ -1,4 +1,5 @@
 pub mod search;
+pub mod selfmate;
 pub mod value;
 
 pub use value::Value;
@@ -1,10 +1,11 @@
 use shogi_core::{Hand, Move, PartialPosition, Piece, ToUsi};
-use std::collections::BTreeSet;
+use std::collections::{BTreeSet, HashMap};
 
 use crate::{
+    df_pn::search::SearchLimits,
     move_ordering::{order_eval_moves, MoveOrderingOptions},
     position_wrapper::{Key, PositionWrapper},
-    tt::{DfPnTable, EvalTable},
+    tt::{DfPnTable, EvalTable, TtStats},
 };
 
 use super::Value;
@@ -14,14 +15,43 @@ const LOG_THRESHOLD: usize = 3;
 #[derive(Clone, Default)]
 pub struct SearchCtx {
     seq: Vec<Move>,
+    /// 探索の打ち切り条件
+    limits: SearchLimits,
+    /// 打ち切り条件に達したか。達した後の評価値は当てにならない。
+    stopped: bool,
 }
 
 #[derive(Clone, Copy, Debug, Default)]
 pub struct SearchStats {
     pub positions_inspected: u64,
+    /// 探索を終えたときの置換表の使用率 (千分率)
+    pub hashfull: u32,
+    /// 探索を終えたときの置換表の利用状況 (表を作ってからの累計)
+    pub tt: TtStats,
 }
 
 impl SearchCtx {
+    /// 打ち切り条件 limits に達したら探索をやめる。
+    pub fn with_limits(limits: SearchLimits) -> Self {
+        Self {
+            limits,
+            ..Default::default()
+        }
+    }
+
+    /// 打ち切り条件に達して探索をやめたか。
+    pub fn is_stopped(&self) -> bool {
+        self.stopped
+    }
+
+    // 打ち切り条件に達していれば、以後の探索をやめる。
+    fn check_limits(&mut self, stats: &SearchStats) -> bool {
+        if !self.stopped && self.limits.is_reached_at(stats.positions_inspected) {
+            self.stopped = true;
+        }
+        self.stopped
+    }
+
     pub fn push(&mut self, mv: Move) {
         self.seq.push(mv);
     }
@@ -110,20 +140,192 @@ pub fn search_with_options_and_stats(
     df_pn_stats: &mut crate::df_pn::search::SearchStats,
     move_ordering: &MoveOrderingOptions,
 ) -> Value {
-    alpha_beta_me_with_options_and_stats(
-        &PositionWrapper::new(position.clone()),
+    search_with_limits(
+        position,
         df_pn,
         evals,
-        Value::ZERO,
-        Value::new(40, 0, 0),
-        &mut BTreeSet::new(),
-        &mut Default::default(),
         verbose,
         stats,
         df_pn_stats,
         move_ordering,
+        &SearchLimits::default(),
     )
-    .0
+    .unwrap_or(Value::INF)
+}
+
+/// 打ち切り条件 limits に達するまで探索する。達したら None を返す。
+#[allow(clippy::too_many_arguments)]
+pub fn search_with_limits(
+    position: &PartialPosition,
+    df_pn: &mut DfPnTable,
+    evals: &mut EvalTable,
+    verbose: bool,
+    stats: &mut SearchStats,
+    df_pn_stats: &mut crate::df_pn::search::SearchStats,
+    move_ordering: &MoveOrderingOptions,
+    limits: &SearchLimits,
+) -> Option<Value> {
+    let mut position = PositionWrapper::new(position.clone());
+    let mut ctx = SearchCtx::with_limits(limits.clone());
+    // df-pn で詰みが証明されていれば、その証明木の手数を超える詰みを探す必要はない。
+    let beta = match mate_length_bound(
+        df_pn,
+        &mut position,
+        true,
+        &mut HashMap::new(),
+        &mut BTreeSet::new(),
+        &mut ctx,
+        stats,
+    ) {
+        Some(plies) if plies < Value::MAX_PLIES => Value::new(plies + 1, 0, 0),
+        _ => Value::new(Value::MAX_PLIES, 0, 0),
+    };
+    // 上限を求める途中で打ち切り条件に達していれば、alpha-beta 法の探索はしない。
+    let value = if ctx.stopped {
+        None
+    } else {
+        let (value, _) = alpha_beta_me_with_options_and_stats(
+            &position,
+            df_pn,
+            evals,
+            Value::ZERO,
+            beta,
+            &mut BTreeSet::new(),
+            &mut ctx,
+            verbose,
+            stats,
+            df_pn_stats,
+            move_ordering,
+        );
+        (!ctx.stopped).then_some(value)
+    };
+    stats.hashfull = evals.hashfull();
+    stats.tt = evals.stats();
+    df_pn_stats.hashfull = df_pn.hashfull();
+    df_pn_stats.tt = df_pn.stats();
+    value
+}
+
+/// 評価値の表をたどって、評価値 opt の最善の詰み手順を求める。打ち切り条件 limits に達したら None を返す。
+///
+/// limits は手順全体に適用する。
+#[allow(clippy::too_many_arguments)]
+pub fn mate_sequence(
+    position: &PartialPosition,
+    df_pn: &mut DfPnTable,
+    evals: &mut EvalTable,
+    opt: Value,
+    verbose: bool,
+    move_ordering: &MoveOrderingOptions,
+    limits: &SearchLimits,
+) -> Option<Vec<Move>> {
+    let mut turn = 0;
+    let mut beta = opt.plies_added_unchecked(1);
+    let mut position = PositionWrapper::new(position.clone());
+    let mut result = Vec::new();
+    let mut ctx = SearchCtx::with_limits(limits.clone());
+    let mut stats = SearchStats::default();
+    loop {
+        let alpha_beta = if turn % 2 == 0 {
+            alpha_beta_me_with_options_and_stats
+        } else {
+            alpha_beta_you_with_options_and_stats
+        };
+        let (_value, mv) = alpha_beta(
+            &position,
+            df_pn,
+            evals,
+            Value::ZERO,
+            beta,
+            &mut BTreeSet::new(),
+            &mut ctx,
+            verbose,
+            &mut stats,
+            &mut Default::default(),
+            move_ordering,
+        );
+        if ctx.stopped {
+            return None;
+        }
+        let Some(mv) = mv else {
+            return Some(result);
+        };
+        result.push(mv);
+        position.make_move(mv);
+        turn += 1;
+        beta = beta.plies_added_unchecked(-1);
+    }
+}
+
+// df-pn の置換表に残っている証明木をたどって、詰み手数の上限を求める。
+// 証明が置換表から失われているか、打ち切り条件に達したら None を返す。
+// position は指しては戻すので、戻ったときには元の局面になっている。
+fn mate_length_bound(
+    df_pn: &DfPnTable,
+    position: &mut PositionWrapper,
+    attacker: bool,
+    memo: &mut HashMap<Key, Option<u32>>,
+    path: &mut BTreeSet<Key>,
+    ctx: &mut SearchCtx,
+    stats: &mut SearchStats,
+) -> Option<u32> {
+    let hash = position.zobrist_hash();
+    if let Some(&result) = memo.get(&hash) {
+        return result;
+    }
+    if path.contains(&hash) {
+        return None;
+    }
+    stats.positions_inspected += 1;
+    if ctx.check_limits(stats) {
+        return None;
+    }
+    let moves = if attacker {
+        position.all_checks()
+    } else {
+        position.all_evasions()
+    };
+    if !attacker && moves.is_empty() {
+        return Some(0);
+    }
+    let entry = df_pn.fetch(&position.df_pn_key())?;
+    let proven = if attacker {
+        entry.is_win()
+    } else {
+        entry.is_loss()
+    };
+    if !proven {
+        return None;
+    }
+    path.insert(hash);
+    let mut result = None;
+    for mv in moves {
+        let undo = position.make_move(mv);
+        let child = mate_length_bound(df_pn, position, !attacker, memo, path, ctx, stats)
+            .map(|plies| plies + 1);
+        position.unmake_move(undo);
+        if ctx.is_stopped() {
+            path.remove(&hash);
+            return None;
+        }
+        result = if attacker {
+            match (result, child) {
+                (Some(a), Some(b)) => Some(core::cmp::min(a, b)),
+                (a, b) => a.or(b),
+            }
+        } else {
+            match child {
+                Some(b) => Some(core::cmp::max(result.unwrap_or(0), b)),
+                None => {
+                    result = None;
+                    break;
+                }
+            }
+        };
+    }
+    path.remove(&hash);
+    memo.insert(hash, result);
+    result
 }
 
 // alpha-beta 法で攻め方の手を探索する。
@@ -223,12 +425,15 @@ pub fn alpha_beta_me_with_options_and_stats(
     move_ordering: &MoveOrderingOptions,
 ) -> (Value, Option<Move>) {
     stats.positions_inspected += 1;
+    if ctx.check_limits(stats) {
+        return (Value::INF, None);
+    }
     if beta.plies() == 0 {
         // 0 手で詰ますことはできない。攻め方にとって最悪の評価値を返す。
         return (Value::INF, None);
     }
-    if let Some((pn, dn)) = df_pn.fetch(position.zobrist_hash()) {
-        if (pn, dn) == (u32::MAX, 0) {
+    if let Some(entry) = df_pn.fetch(&position.df_pn_key()) {
+        if entry.value() == (u32::MAX, 0) {
             // もう詰まないことが分かっている。攻め方にとって最悪の評価値を返す。
             return (Value::INF, None);
         }
@@ -240,11 +445,12 @@ pub fn alpha_beta_me_with_options_and_stats(
             position,
             (10, 10),
             crate::df_pn::search::NodeKind::Or,
-            false,
             &mut Default::default(),
             verbose,
             df_pn_stats,
             move_ordering,
+            &Default::default(),
+            &ctx.limits,
         );
         if mate_result == (u32::MAX, 0) {
             // 不詰を読み切れたので攻め方にとって最悪の評価値を返す。
@@ -270,6 +476,31 @@ pub fn alpha_beta_me_with_options_and_stats(
         return (Value::INF, None);
     }
 
+    // 1 手詰めがあればそれより短い詰みはないので、持ち駒が最も多く余るものを選ぶ。
+    let mut mate_in_one = None;
+    for mv in position.mates_in_one_among(&all) {
+        let mut next = position.clone();
+        next.make_move(mv);
+        let inner = next.inner();
+        let pieces: u8 = Hand::all_hand_pieces()
+            .map(|piece_kind| {
+                inner
+                    .hand(Piece::new(piece_kind, inner.side_to_move().flip()))
+                    .unwrap()
+            })
+            .sum();
+        let value = Value::new(1, pieces as u32, 0);
+        if mate_in_one.is_none_or(|(best, _)| value < best) {
+            mate_in_one = Some((value, mv));
+        }
+    }
+    if let Some((value, mv)) = mate_in_one {
+        if value < beta {
+            evals.insert(position.zobrist_hash(), (value, Some(mv)));
+            return (value, Some(mv));
+        }
+    }
+
     if seen.contains(&position.zobrist_hash()) {
         return (Value::INF, None);
     }
@@ -299,6 +530,11 @@ pub fn alpha_beta_me_with_options_and_stats(
         )
         .0;
         ctx.pop();
+        if ctx.stopped {
+            // 打ち切った部分木の評価値は記録しない。
+            seen.remove(&position.zobrist_hash());
+            return (Value::INF, None);
+        }
         let eval = eval.plies_added_unchecked(1);
         if eval < beta {
             best = Some(mv);
@@ -426,8 +662,11 @@ pub fn alpha_beta_you_with_options_and_stats(
     move_ordering: &MoveOrderingOptions,
 ) -> (Value, Option<Move>) {
     stats.positions_inspected += 1;
-    if let Some((dn, pn)) = df_pn.fetch(position.zobrist_hash()) {
-        if (pn, dn) == (u32::MAX, 0) {
+    if ctx.check_limits(stats) {
+        return (Value::INF, None);
+    }
+    if let Some(entry) = df_pn.fetch(&position.df_pn_key()) {
+        if entry.value() == (0, u32::MAX) {
             // もう詰まないことが分かっている。攻め方にとって最悪の評価値を返す。
             return (Value::INF, None);
         }
@@ -499,6 +738,10 @@ pub fn alpha_beta_you_with_options_and_stats(
         )
         .0;
         ctx.pop();
+        if ctx.stopped {
+            seen.remove(&position.zobrist_hash());
+            return (Value::INF, None);
+        }
         let eval = eval.plies_added_unchecked(1);
         if eval > alpha {
             best = Some(mv);
@@ -527,7 +770,58 @@ pub fn alpha_beta_you_with_options_and_stats(
 #[cfg(test)]
 mod tests {
     use super::*;
-    use shogi_core::{Square, ToUsi};
+    use shogi_core::{Color, Square, ToUsi};
+
+    // df-pn と eval で解き、手順が合法で最後に詰んでいることを確かめる。
+    fn assert_solved(mut position: PartialPosition, plies: Option<u32>, name: &str) {
+        let mut df_pn = DfPnTable::new(1 << 15);
+        let mut evals = EvalTable::new(1 << 15);
+        let mate_result =
+            crate::df_pn::search::df_pn(&df_pn, &PositionWrapper::new(position.clone()), false);
+        let Some(plies) = plies else {
+            assert_eq!(mate_result, (u32::MAX, 0), "{}", name);
+            return;
+        };
+        assert_eq!(mate_result, (0, u32::MAX), "{}", name);
+        let result = search(&position, &mut df_pn, &mut evals, false);
+        assert_eq!(result.plies(), plies, "{}", name);
+        let sequence = find_mate_sequence(&mut df_pn, &mut evals, &position, result);
+        assert_eq!(sequence.len(), plies as usize, "{}", name);
+        for (index, &mv) in sequence.iter().enumerate() {
+            assert!(
+                shogi_legality_lite::is_legal_partial_lite(&position, mv),
+                "{} {}",
+                name,
+                mv.to_usi_owned()
+            );
+            position.make_move(mv).unwrap();
+            if index % 2 == 0 {
+                assert!(shogi_legality_lite::is_in_check_partial_lite(&position));
+            }
+        }
+        assert_eq!(
+            shogi_legality_lite::prelegality::is_mate(&position),
+            Some(true),
+            "{}",
+            name
+        );
+    }
+
+    // 盤を 180 度回して先後を入れ替える。
+    fn flipped(position: &PartialPosition) -> PartialPosition {
+        let mut result = PartialPosition::empty();
+        for square in Square::all() {
+            if let Some(piece) = position.piece_at(square) {
+                let piece = Piece::new(piece.piece_kind(), piece.color().flip());
+                result.piece_set(square.flip(), Some(piece));
+            }
+        }
+        for color in Color::all() {
+            *result.hand_of_a_player_mut(color.flip()) = position.hand_of_a_player(color);
+        }
+        result.side_to_move_set(position.side_to_move().flip());
+        result
+    }
 
     fn find_mate_sequence(
         df_pn: &mut DfPnTable,
@@ -535,44 +829,16 @@ mod tests {
         position: &PartialPosition,
         opt: Value,
     ) -> Vec<Move> {
-        let mut turn = 0;
-        let mut beta = opt.plies_added_unchecked(1);
-        let mut position = PositionWrapper::new(position.clone());
-        let mut result = Vec::new();
-        let mut ctx = SearchCtx::default();
-        loop {
-            let (_value, mv) = if turn % 2 == 0 {
-                alpha_beta_me(
-                    &position,
-                    df_pn,
-                    evals,
-                    Value::ZERO,
-                    beta,
-                    &mut BTreeSet::new(),
-                    &mut ctx,
-                    false,
-                )
-            } else {
-                alpha_beta_you(
-                    &position,
-                    df_pn,
-                    evals,
-                    Value::ZERO,
-                    beta,
-                    &mut BTreeSet::new(),
-                    &mut ctx,
-                    false,
-                )
-            };
-            if let Some(mv) = mv {
-                result.push(mv);
-                position.make_move(mv);
-            } else {
-                return result;
-            }
-            turn += 1;
-            beta = beta.plies_added_unchecked(-1);
-        }
+        mate_sequence(
+            position,
+            df_pn,
+            evals,
+            opt,
+            false,
+            &MoveOrderingOptions::default(),
+            &SearchLimits::default(),
+        )
+        .unwrap()
     }
 
     #[test]
@@ -588,7 +854,7 @@ mod tests {
         let mut eval = EvalTable::new(1 << 20);
 
         let _mate_result =
-            crate::df_pn::search::df_pn(&mut df_pn, &PositionWrapper::new(position.clone()), false);
+            crate::df_pn::search::df_pn(&df_pn, &PositionWrapper::new(position.clone()), false);
 
         let result = search(&position, &mut df_pn, &mut eval, false);
         eprintln!("result = {:?}", result);
@@ -600,6 +866,110 @@ mod tests {
         assert_eq!(sequence.len(), 5);
     }
 
+    #[test]
+    fn search_stops_at_limits() {
+        use shogi_usi_parser::FromUsi;
+
+        let position =
+            PartialPosition::from_usi("sfen 3g1ks2/6g2/4S4/7B1/9/9/9/9/9 b G2rbg2s4n4l18p 1")
+                .unwrap();
+        let mut df_pn = DfPnTable::new(1 << 15);
+        crate::df_pn::search::df_pn(&df_pn, &PositionWrapper::new(position.clone()), false);
+        let mut run = |limits: &SearchLimits| {
+            let mut eval = EvalTable::new(1 << 10);
+            let mut stats = SearchStats::default();
+            let value = search_with_limits(
+                &position,
+                &mut df_pn,
+                &mut eval,
+                false,
+                &mut stats,
+                &mut Default::default(),
+                &MoveOrderingOptions::default(),
+                limits,
+            );
+            (value, stats.positions_inspected, eval.len())
+        };
+
+        let (value, nodes, stored) = run(&SearchLimits {
+            max_nodes: Some(3),
+            ..Default::default()
+        });
+        assert_eq!(value, None);
+        assert!(nodes <= 3);
+        // 打ち切った部分木の評価値は残らない。
+        assert!(stored <= 2);
+
+        let (value, _, _) = run(&SearchLimits::default());
+        assert_eq!(value.map(|value| value.plies()), Some(5));
+    }
+
+    #[test]
+    fn mate_sequence_stops_at_limits() {
+        use shogi_usi_parser::FromUsi;
+
+        let position = PartialPosition::from_usi("sfen 4k4/9/4P4/9/9/9/9/9/4K4 b G 1").unwrap();
+        let mut df_pn = DfPnTable::new(1 << 10);
+        let mut evals = EvalTable::new(1 << 10);
+        let value = search(&position, &mut df_pn, &mut evals, false);
+        let mut run = |evals: &mut EvalTable, limits: &SearchLimits| {
+            mate_sequence(
+                &position,
+                &mut df_pn,
+                evals,
+                value,
+                false,
+                &MoveOrderingOptions::default(),
+                limits,
+            )
+        };
+        let limits = SearchLimits {
+            max_nodes: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(run(&mut EvalTable::new(1 << 10), &limits), None);
+        assert_eq!(
+            run(&mut evals, &SearchLimits::default()),
+            Some(vec![Move::from_usi("G*5b").unwrap()])
+        );
+    }
+
+    #[test]
+    fn mate_length_bound_stops_at_limits() {
+        use shogi_usi_parser::FromUsi;
+
+        let position =
+            PartialPosition::from_usi("sfen 3g1ks2/6g2/4S4/7B1/9/9/9/9/9 b G2rbg2s4n4l18p 1")
+                .unwrap();
+        let df_pn = DfPnTable::new(1 << 15);
+        let mut position = PositionWrapper::new(position);
+        crate::df_pn::search::df_pn(&df_pn, &position, false);
+        let mut run = |limits: SearchLimits| {
+            let mut ctx = SearchCtx::with_limits(limits);
+            let bound = mate_length_bound(
+                &df_pn,
+                &mut position,
+                true,
+                &mut HashMap::new(),
+                &mut BTreeSet::new(),
+                &mut ctx,
+                &mut SearchStats::default(),
+            );
+            (bound, ctx.is_stopped())
+        };
+
+        let (bound, stopped) = run(SearchLimits {
+            max_nodes: Some(2),
+            ..Default::default()
+        });
+        assert_eq!(bound, None);
+        assert!(stopped);
+
+        let (bound, stopped) = run(SearchLimits::default());
+        assert!(bound.is_some_and(|plies| plies >= 5));
+        assert!(!stopped);
+    }
+
     #[test]
     fn solve_mate_problem_works_1() {
         use shogi_usi_parser::FromUsi;
@@ -625,7 +995,7 @@ mod tests {
         let mut evals = EvalTable::new(1 << 15);
 
         let _mate_result =
-            crate::df_pn::search::df_pn(&mut df_pn, &PositionWrapper::new(position.clone()), false);
+            crate::df_pn::search::df_pn(&df_pn, &PositionWrapper::new(position.clone()), false);
 
         let result = search(&position, &mut df_pn, &mut evals, false);
         eprintln!("result = {:?}", result);
@@ -656,7 +1026,7 @@ mod tests {
         let mut eval = EvalTable::new(1 << 15);
 
         let _mate_result =
-            crate::df_pn::search::df_pn(&mut df_pn, &PositionWrapper::new(position.clone()), false);
+            crate::df_pn::search::df_pn(&df_pn, &PositionWrapper::new(position.clone()), false);
 
         let result = search(&position, &mut df_pn, &mut eval, false);
         eprintln!("result = {:?}", result);
@@ -672,4 +1042,96 @@ mod tests {
         }
         assert_eq!(sequence.len(), 3);
     }
+
+    #[test]
+    fn mate_length_bound_follows_proof() {
+        use shogi_usi_parser::FromUsi;
+
+        let position =
+            PartialPosition::from_usi("sfen 5kgnl/9/4+B1pp1/8p/9/9/9/9/9 b 2S2rb3g2s3n3l15p 1")
+                .unwrap();
+        let mut position = PositionWrapper::new(position);
+        let df_pn = DfPnTable::new(1 << 15);
+        // 証明される前は分からない
+        let bound = mate_length_bound(
+            &df_pn,
+            &mut position,
+            true,
+            &mut HashMap::new(),
+            &mut BTreeSet::new(),
+            &mut SearchCtx::default(),
+            &mut SearchStats::default(),
+        );
+        assert_eq!(bound, None);
+
+        crate::df_pn::search::df_pn(&df_pn, &position, false);
+        let bound = mate_length_bound(
+            &df_pn,
+            &mut position,
+            true,
+            &mut HashMap::new(),
+            &mut BTreeSet::new(),
+            &mut SearchCtx::default(),
+            &mut SearchStats::default(),
+        )
+        .unwrap();
+        assert!(bound >= 9);
+        assert_eq!(bound % 2, 1);
+    }
+
+    #[test]
+    fn double_king_problems_are_solved() {
+        use shogi_usi_parser::FromUsi;
+
+        // 双玉の問題と、総当たりで確かめた最短の詰み手数。
+        // 玉方には逆王手をかける応手があり、攻め方はそれを外しながら王手をかけなければならない。
+        let problems = [
+            ("4g4/5k3/K8/9/9/4L1Lr+R/9/4l4/9 b Bn 1", Some(5)),
+            ("8k/9/3G1RK2/9/9/8l/5p3/9/9 b BLr 1", Some(5)),
+            ("9/9/l7k/9/7R1/5R3/6K2/9/9 b Sl 1", Some(5)),
+            ("4+B2k1/9/6K2/2gR5/9/9/9/9/1s4g2 b Bsl 1", Some(5)),
+            // 逆王手を外さない駒打ちで詰むように見えるが、自玉に王手がかかったままなので指せない。
+            ("8k/9/1b7/4L3K/2sG5/8L/9/9/9 b RBr 1", Some(5)),
+            ("5p2k/9/5B3/8K/8R/9/9/l8/9 b NLbl 1", Some(5)),
+            ("9/8k/9/6+R2/7K1/p8/9/8g/g8 b RBl 1", Some(5)),
+            ("r7k/9/8P/9/9/9/9/9/K8 b G 1", None),
+        ];
+        for (sfen, plies) in problems {
+            let position = PartialPosition::from_usi(&format!("sfen {}", sfen)).unwrap();
+            assert_solved(position, plies, sfen);
+        }
+    }
+
+    #[test]
+    fn white_attacker_problems_are_solved() {
+        use shogi_usi_parser::FromUsi;
+
+        // 先手の問題を盤ごと回した後手番の問題も、同じ手数で解ける。
+        let problems = [
+            ("3g1ks2/6g2/4S4/7B1/9/9/9/9/9 b G2rbg2s4n4l18p 1", Some(5)),
+            ("5kgnl/9/4+B1pp1/8p/9/9/9/9/9 b 2S2rb3g2s3n3l15p 1", Some(9)),
+            ("8k/9/1b7/4L3K/2sG5/8L/9/9/9 b RBr 1", Some(5)),
+            ("r7k/9/8P/9/9/9/9/9/K8 b G 1", None),
+        ];
+        for (sfen, plies) in problems {
+            let position = flipped(&PartialPosition::from_usi(&format!("sfen {}", sfen)).unwrap());
+            assert_eq!(position.side_to_move(), Color::White);
+            assert_solved(position.clone(), plies, sfen);
+            let answer = crate::search(&position, 60_000);
+            let ok = answer.inner.unwrap();
+            match plies {
+                Some(plies) => {
+                    assert!(matches!(ok.resolution, crate::Resolution::Mate), "{}", sfen);
+                    let root = ok.branches.iter().find(|branch| branch.moves.is_empty());
+                    let eval = root.unwrap().eval.unwrap();
+                    assert_eq!(eval.num_moves, plies as i32, "{}", sfen);
+                }
+                None => assert!(
+                    matches!(ok.resolution, crate::Resolution::NoMate),
+                    "{}",
+                    sfen
+                ),
+            }
+        }
+    }
 }
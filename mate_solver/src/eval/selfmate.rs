@@ -0,0 +1,292 @@
+//! 自玉詰の最短手順の探索。
+//!
+//! df-pn で自玉詰と分かった局面について、攻め方はなるべく短く、玉方はなるべく長く逃れる手順を求める。
+//! 攻め方の手番での残り手数を 2 手ずつ伸ばしながら、その手数以内に自玉詰にできるかを調べる。
+use std::collections::HashMap;
+
+use shogi_core::Move;
+use shogi_legality_lite::is_in_check_partial_lite;
+
+use crate::{
+    df_pn::search::{NodeKind, SearchLimits},
+    position_wrapper::{Key, PositionWrapper},
+    tt::DfPnTable,
+};
+
+use super::{search::SearchStats, Value};
+
+// 勝ち負けを覚えておく局面の個数の上限。超えたら忘れる。
+const MAX_MEMO: usize = 1 << 20;
+
+/// 自玉詰の最短手順を返す。
+///
+/// position は df_pn で自玉詰と証明済みの局面とする。
+/// df_pn は、王手をかけても自玉詰にならないと分かっている手を省くのに使う。
+/// [`Value::MAX_PLIES`] 手以内に自玉詰にできなければ None を返す。
+pub fn search(position: &PositionWrapper, df_pn: &DfPnTable) -> Option<Vec<Move>> {
+    search_with_stats(position, df_pn, &mut SearchStats::default())
+}
+
+pub fn search_with_stats(
+    position: &PositionWrapper,
+    df_pn: &DfPnTable,
+    stats: &mut SearchStats,
+) -> Option<Vec<Move>> {
+    search_with_limits(position, df_pn, stats, &SearchLimits::default())
+}
+
+/// 打ち切り条件 limits に達するまで探索する。達したときも None を返す。
+pub fn search_with_limits(
+    position: &PositionWrapper,
+    df_pn: &DfPnTable,
+    stats: &mut SearchStats,
+    limits: &SearchLimits,
+) -> Option<Vec<Move>> {
+    let mut searcher = Searcher {
+        df_pn,
+        stats,
+        limits,
+        wins: HashMap::new(),
+        failures: HashMap::new(),
+    };
+    let mut position = position.clone();
+    let mut plies = 0;
+    while !searcher.within(&mut position, plies, true)? {
+        plies += 2;
+        if plies > Value::MAX_PLIES {
+            return None;
+        }
+    }
+    let mut result = vec![];
+    while plies > 0 {
+        let attacker = result.len() % 2 == 0;
+        let moves = if attacker {
+            position.all_checks()
+        } else {
+            position.all_evasions()
+        };
+        // 攻め方は残り手数で自玉詰にできる手を、玉方はそれより 2 手早くは自玉詰にならない手を選ぶ。
+        let mut chosen = None;
+        for mv in moves {
+            let undo = position.make_move(mv);
+            let keeps = if attacker {
+                searcher.within(&mut position, plies - 1, false)
+            } else if plies < 3 {
+                Some(true)
+            } else {
+                searcher
+                    .within(&mut position, plies - 3, true)
+                    .map(|found| !found)
+            };
+            position.unmake_move(undo);
+            if keeps? {
+                chosen = Some(mv);
+                break;
+            }
+        }
+        // 自玉詰にできると分かった後なので見つかるはずだが、見つからなければ手順を返さない。
+        let mv = chosen?;
+        position.make_move(mv);
+        result.push(mv);
+        plies -= 1;
+    }
+    Some(result)
+}
+
+struct Searcher<'a> {
+    df_pn: &'a DfPnTable,
+    stats: &'a mut SearchStats,
+    limits: &'a SearchLimits,
+    // 残り手数以内に自玉詰にできると分かった局面と、その手数。MAX_MEMO 個までしか覚えない。
+    wins: HashMap<Key, u32>,
+    // 残り手数以内には自玉詰にできないと分かった局面と、その手数。MAX_MEMO 個までしか覚えない。
+    failures: HashMap<Key, u32>,
+}
+
+impl Searcher<'_> {
+    // 残り plies 手以内に自玉詰にできるかを返す。打ち切り条件に達したら None。
+    fn within(
+        &mut self,
+        position: &mut PositionWrapper,
+        plies: u32,
+        attacker: bool,
+    ) -> Option<bool> {
+        self.stats.positions_inspected += 1;
+        if self.limits.is_reached_at(self.stats.positions_inspected) {
+            return None;
+        }
+        let hash = position.zobrist_hash();
+        if self.wins.get(&hash).is_some_and(|&won| won <= plies) {
+            return Some(true);
+        }
+        if self
+            .failures
+            .get(&hash)
+            .is_some_and(|&failed| failed >= plies)
+        {
+            return Some(false);
+        }
+        let found = if attacker {
+            self.attack(position, plies)?
+        } else {
+            self.defend(position, plies)?
+        };
+        let memo = if found {
+            &mut self.wins
+        } else {
+            &mut self.failures
+        };
+        if memo.len() >= MAX_MEMO {
+            memo.clear();
+        }
+        memo.insert(hash, plies);
+        Some(found)
+    }
+
+    fn attack(&mut self, position: &mut PositionWrapper, plies: u32) -> Option<bool> {
+        // 攻め方の玉が詰んでいれば自玉詰の完成。
+        if is_in_check_partial_lite(position.inner()) && position.all_evasions().is_empty() {
+            return Some(true);
+        }
+        if plies == 0 {
+            return Some(false);
+        }
+        for mv in position.all_checks() {
+            let undo = position.make_move(mv);
+            let hopeless = self
+                .df_pn
+                .fetch(&NodeKind::SelfAnd.key(position))
+                .is_some_and(|entry| entry.is_win() && entry.path.is_none());
+            let found = if hopeless {
+                Some(false)
+            } else {
+                self.within(position, plies - 1, false)
+            };
+            position.unmake_move(undo);
+            if found? {
+                return Some(true);
+            }
+        }
+        Some(false)
+    }
+
+    fn defend(&mut self, position: &mut PositionWrapper, plies: u32) -> Option<bool> {
+        let evasions = position.all_evasions();
+        // 応手がなければ玉方の玉が詰んでいて、自玉詰にはならない。
+        if evasions.is_empty() || plies == 0 {
+            return Some(false);
+        }
+        for mv in evasions {
+            let undo = position.make_move(mv);
+            let found = self.within(position, plies - 1, true);
+            position.unmake_move(undo);
+            if !found? {
+                return Some(false);
+            }
+        }
+        Some(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::df_pn::search::{
+        selfmate_with_limits, Outcome, SearchLimits, SearchStats as DfPnStats,
+    };
+    use shogi_core::PartialPosition;
+    use shogi_usi_parser::FromUsi;
+
+    // 置換表を使わずに、残り plies 手以内に自玉詰にできるかを調べる。
+    fn within_brute_force(position: &mut PositionWrapper, plies: u32, attacker: bool) -> bool {
+        let moves = if attacker {
+            position.all_checks()
+        } else {
+            position.all_evasions()
+        };
+        if attacker
+            && is_in_check_partial_lite(position.inner())
+            && position.all_evasions().is_empty()
+        {
+            return true;
+        }
+        if plies == 0 || (!attacker && moves.is_empty()) {
+            return false;
+        }
+        let mut results = moves.into_iter().map(|mv| {
+            let undo = position.make_move(mv);
+            let result = within_brute_force(position, plies - 1, !attacker);
+            position.unmake_move(undo);
+            result
+        });
+        if attacker {
+            results.any(|result| result)
+        } else {
+            results.all(|result| result)
+        }
+    }
+
+    #[test]
+    fn lines_are_shortest() {
+        for sfen in [
+            "8K/9/7+bk/9/7+B1/9/9/9/9 b G 1",
+            "5S+rk1/9/5g1PK/9/7b1/9/9/9/9 b - 1",
+            "7sk/9/8K/4pS3/5S1+r1/9/9/9/9 b G 1",
+            "4s3K/6R2/5k3/5B3/5L1+r1/9/9/9/9 b G 1",
+            "7k1/9/4s1K2/6N2/5R1+r1/9/9/9/9 b G 1",
+            "6+b2/5k1pK/4L3G/4+B4/9/9/9/9/9 b R 1",
+            "6k1K/4+B4/6P+R1/6+r2/7b1/9/9/9/9 b - 1",
+            "4RK3/9/5k1+B1/5p3/5S2+r/9/9/9/9 b N 1",
+        ] {
+            let position = PartialPosition::from_usi(&format!("sfen {}", sfen)).unwrap();
+            let mut position = PositionWrapper::new(position);
+            let df_pn = DfPnTable::new(1 << 12);
+            let outcome = selfmate_with_limits(
+                &df_pn,
+                &position,
+                false,
+                &mut DfPnStats::default(),
+                &Default::default(),
+                &Default::default(),
+                &SearchLimits::default(),
+            );
+            assert_eq!(outcome, Outcome::Mate, "{}", sfen);
+            let moves = search(&position, &df_pn).unwrap();
+            let plies = moves.len() as u32;
+            assert!(within_brute_force(&mut position, plies, true), "{}", sfen);
+            assert!(
+                !within_brute_force(&mut position, plies - 2, true),
+                "{}",
+                sfen
+            );
+            // 攻め方は王手をかけ続け、最後に玉方が攻め方の玉を詰ませる。
+            for (index, &mv) in moves.iter().enumerate() {
+                position.make_move(mv);
+                if index % 2 == 0 {
+                    assert!(is_in_check_partial_lite(position.inner()), "{}", sfen);
+                }
+            }
+            assert!(is_in_check_partial_lite(position.inner()), "{}", sfen);
+            assert!(position.all_evasions().is_empty(), "{}", sfen);
+        }
+    }
+
+    #[test]
+    fn search_stops_at_limits() {
+        let position =
+            PartialPosition::from_usi("sfen 5k2K/4R4/7+b1/4+R4/9/9/9/9/9 b - 1").unwrap();
+        let position = PositionWrapper::new(position);
+        let df_pn = DfPnTable::new(1 << 12);
+        let limits = SearchLimits {
+            max_nodes: Some(3),
+            ..Default::default()
+        };
+        let mut stats = SearchStats::default();
+        assert_eq!(
+            search_with_limits(&position, &df_pn, &mut stats, &limits),
+            None
+        );
+        assert_eq!(stats.positions_inspected, 3);
+        assert_eq!(search(&position, &df_pn).map(|moves| moves.len()), Some(4));
+    }
+}
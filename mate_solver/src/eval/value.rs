@@ -12,6 +12,8 @@ impl Value {
     // 不詰。
     pub const INF: Self = Self(0xffff_ffff);
     const PLY_MASK: u32 = 0xfff0_0000;
+    /// 表せる手数の上限。これ以上の手数の詰みは扱えない。
+    pub const MAX_PLIES: u32 = (Self::PLY_MASK >> 20) - 1;
     pub fn new(plies: u32, pieces: u32, futile: u32) -> Self {
         Self(plies << 20 | (0xff - pieces) << 12 | (0xfff - futile))
     }
@@ -0,0 +1,119 @@
+//! 持ち駒の優越関係の計算。
+use shogi_core::{Hand, PieceKind};
+
+/// 持ち駒の各種類の枚数の上限。[`Hand::all_hand_pieces`] の順に並んでいる。
+pub const MAX_COUNTS: [u8; Hand::NUM_HAND_PIECES] = [18, 4, 4, 4, 4, 2, 2];
+
+/// 各種類の枚数から持ち駒を作る。
+pub fn from_counts(counts: [u8; Hand::NUM_HAND_PIECES]) -> Hand {
+    let mut hand = Hand::new();
+    for (piece_kind, count) in Hand::all_hand_pieces().zip(counts) {
+        for _ in 0..count {
+            hand = hand.added(piece_kind).unwrap();
+        }
+    }
+    hand
+}
+
+/// 各種類の枚数を取り出す。
+#[inline]
+pub fn counts(hand: Hand) -> [u8; Hand::NUM_HAND_PIECES] {
+    let mut result = [0; Hand::NUM_HAND_PIECES];
+    for (index, piece_kind) in Hand::all_hand_pieces().enumerate() {
+        // Safety: piece_kind は持ち駒になれる駒
+        result[index] = unsafe { hand.count(piece_kind).unwrap_unchecked() };
+    }
+    result
+}
+
+// pack で各種類の枚数を置くビットの位置。歩は 5 ビット、他は 3 ビットずつ使う。
+const PACK_SHIFTS: [u32; Hand::NUM_HAND_PIECES] = [0, 5, 8, 11, 14, 17, 20];
+
+/// 持ち駒を 32 ビットに詰める。各種類の枚数は上限以下でなければならない。[`unpack`] で元に戻る。
+#[inline]
+pub fn pack(hand: Hand) -> u32 {
+    counts(hand)
+        .iter()
+        .zip(PACK_SHIFTS)
+        .fold(0, |packed, (&count, shift)| {
+            packed | (count as u32) << shift
+        })
+}
+
+/// pack で詰めた持ち駒を元に戻す。
+#[inline]
+pub fn unpack(packed: u32) -> Hand {
+    from_counts(core::array::from_fn(|i| {
+        let mask = if i == 0 { 0x1f } else { 0x7 };
+        (packed >> PACK_SHIFTS[i] & mask) as u8
+    }))
+}
+
+/// すべての種類の駒を上限枚数ずつ持っている持ち駒。
+pub fn full() -> Hand {
+    from_counts(MAX_COUNTS)
+}
+
+/// a がすべての種類について b 以上の枚数を持っているか (a が b に優越しているか)。
+#[inline]
+pub fn is_superior_or_equal(a: Hand, b: Hand) -> bool {
+    let a = counts(a);
+    let b = counts(b);
+    (0..Hand::NUM_HAND_PIECES).all(|i| a[i] >= b[i])
+}
+
+/// 種類ごとに多い方の枚数を取った持ち駒。
+pub fn max(a: Hand, b: Hand) -> Hand {
+    let a = counts(a);
+    let b = counts(b);
+    from_counts(core::array::from_fn(|i| a[i].max(b[i])))
+}
+
+/// 種類ごとに少ない方の枚数を取った持ち駒。
+pub fn min(a: Hand, b: Hand) -> Hand {
+    let a = counts(a);
+    let b = counts(b);
+    from_counts(core::array::from_fn(|i| a[i].min(b[i])))
+}
+
+/// piece_kind を 1 枚加える。上限を超える場合は上限で止める。
+pub fn added_saturating(hand: Hand, piece_kind: PieceKind) -> Hand {
+    let index = piece_kind.array_index();
+    if hand.count(piece_kind) >= Some(MAX_COUNTS[index]) {
+        return hand;
+    }
+    hand.added(piece_kind).unwrap()
+}
+
+/// piece_kind を 1 枚取り除く。持っていない場合はそのまま返す。
+pub fn removed_saturating(hand: Hand, piece_kind: PieceKind) -> Hand {
+    hand.removed(piece_kind).unwrap_or(hand)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pack_round_trips() {
+        for counts in [[0; 7], MAX_COUNTS, [3, 0, 1, 4, 0, 2, 1]] {
+            let hand = from_counts(counts);
+            assert_eq!(unpack(pack(hand)), hand);
+        }
+        assert_ne!(pack(from_counts([1, 0, 0, 0, 0, 0, 0])), pack(Hand::new()));
+    }
+
+    #[test]
+    fn superiority_works() {
+        let a = from_counts([2, 0, 1, 0, 0, 0, 1]);
+        let b = from_counts([1, 0, 1, 0, 0, 0, 0]);
+        assert!(is_superior_or_equal(a, b));
+        assert!(!is_superior_or_equal(b, a));
+        assert!(is_superior_or_equal(a, a));
+        assert_eq!(max(a, b), a);
+        assert_eq!(min(a, b), b);
+        assert_eq!(counts(full()), MAX_COUNTS);
+        assert_eq!(added_saturating(full(), PieceKind::Rook), full());
+        assert_eq!(removed_saturating(b, PieceKind::Rook), b);
+    }
+}
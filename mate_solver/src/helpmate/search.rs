@@ -0,0 +1,289 @@
+//! 協力詰 (ばか詰) の探索。
+//!
+//! 攻め方は王手だけを、玉方は王手を外す手だけを指し、両者が協力して玉方の玉を詰ませる手順を探す。
+//! 玉方は最長の応手を選ばなくてよいので、df-pn の AND ノードも OR ノードのように扱うことになる。
+//! ここでは手数を 2 手ずつ伸ばす反復深化で探すので、最初に見つかった手順が最短になる。
+use std::collections::HashMap;
+
+use shogi_core::{Move, PartialPosition};
+
+use crate::{
+    df_pn::search::SearchLimits,
+    position_wrapper::{Key, PositionWrapper},
+};
+
+/// 協力詰の探索結果。
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Outcome {
+    /// 最短の詰み手順。unique は同じ手数の別の手順がないか。
+    Mate { moves: Vec<Move>, unique: bool },
+    /// max_plies 手以内には詰まない。
+    NoMate,
+    /// 打ち切り条件に達して分からなかった。
+    Unknown,
+}
+
+// 詰まないと分かった局面を覚えておく個数の上限。超えたら忘れる。
+const MAX_FAILURES: usize = 1 << 20;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SearchStats {
+    pub positions_inspected: u64,
+}
+
+/// max_plies 手以内の最短の協力詰を探す。
+pub fn search(position: &PartialPosition, max_plies: u32) -> Outcome {
+    search_with_limits(
+        position,
+        max_plies,
+        &mut SearchStats::default(),
+        &SearchLimits::default(),
+    )
+}
+
+/// 打ち切り条件 limits に達するまで探索する。
+pub fn search_with_limits(
+    position: &PartialPosition,
+    max_plies: u32,
+    stats: &mut SearchStats,
+    limits: &SearchLimits,
+) -> Outcome {
+    let mut position = PositionWrapper::new(position.clone());
+    let mut searcher = Searcher {
+        failures: HashMap::new(),
+        stats,
+        limits,
+        line: vec![],
+        first: vec![],
+        solutions: 0,
+    };
+    for plies in (1..=max_plies).step_by(2) {
+        // 前の反復で詰まないと分かった手数は、この反復の残り手数より短いのでほとんど使えない。
+        searcher.failures.clear();
+        match searcher.visit(&mut position, plies, true) {
+            None => return Outcome::Unknown,
+            Some(false) => {}
+            Some(true) => {
+                return Outcome::Mate {
+                    moves: searcher.first,
+                    unique: searcher.solutions == 1,
+                }
+            }
+        }
+    }
+    Outcome::NoMate
+}
+
+struct Searcher<'a> {
+    // 残り手数以内では詰まないと分かった局面と、その手数。MAX_FAILURES 個までしか覚えない。
+    // 反復深化の途中では、根から来られる局面にそれより短い詰みはないので、ちょうどの手数で詰まないことと同じになる。
+    failures: HashMap<Key, u32>,
+    stats: &'a mut SearchStats,
+    limits: &'a SearchLimits,
+    // 根からの手順
+    line: Vec<Move>,
+    // 最初に見つかった詰み手順
+    first: Vec<Move>,
+    // 見つかった詰み手順の数。2 つ見つかったら探索をやめる。
+    solutions: usize,
+}
+
+impl Searcher<'_> {
+    // 残り plies 手ちょうどで詰む手順があるかを返す。打ち切り条件に達したら None。
+    fn visit(
+        &mut self,
+        position: &mut PositionWrapper,
+        plies: u32,
+        attacker: bool,
+    ) -> Option<bool> {
+        self.stats.positions_inspected += 1;
+        if self.limits.is_reached_at(self.stats.positions_inspected) {
+            return None;
+        }
+        let hash = position.zobrist_hash();
+        if self
+            .failures
+            .get(&hash)
+            .is_some_and(|&failed| failed >= plies)
+        {
+            return Some(false);
+        }
+        let mut found = false;
+        if attacker && plies == 1 {
+            let checks = position.all_checks();
+            for mv in position.mates_in_one_among(&checks) {
+                found = true;
+                if self.solutions == 0 {
+                    self.first = self.line.clone();
+                    self.first.push(mv);
+                }
+                self.solutions += 1;
+                if self.solutions >= 2 {
+                    break;
+                }
+            }
+        } else {
+            let moves = if attacker {
+                position.all_checks()
+            } else {
+                position.all_evasions()
+            };
+            for mv in moves {
+                let undo = position.make_move(mv);
+                self.line.push(mv);
+                let child = self.visit(position, plies - 1, !attacker);
+                self.line.pop();
+                position.unmake_move(undo);
+                found |= child?;
+                if self.solutions >= 2 {
+                    break;
+                }
+            }
+        }
+        if !found {
+            if self.failures.len() >= MAX_FAILURES {
+                self.failures.clear();
+            }
+            self.failures.insert(hash, plies);
+        }
+        Some(found)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shogi_core::ToUsi;
+    use shogi_usi_parser::FromUsi;
+
+    fn solve(sfen: &str, max_plies: u32) -> Outcome {
+        search(
+            &PartialPosition::from_usi(&format!("sfen {}", sfen)).unwrap(),
+            max_plies,
+        )
+    }
+
+    // 置換表を使わずに、plies 手ちょうどで詰む手順をすべて数える。
+    fn count_brute_force(position: &mut PositionWrapper, plies: u32, attacker: bool) -> usize {
+        let moves = if attacker {
+            position.all_checks()
+        } else {
+            position.all_evasions()
+        };
+        if plies == 0 {
+            return usize::from(!attacker && moves.is_empty());
+        }
+        let mut count = 0;
+        for mv in moves {
+            let undo = position.make_move(mv);
+            count += count_brute_force(position, plies - 1, !attacker);
+            position.unmake_move(undo);
+        }
+        count
+    }
+
+    fn usi(outcome: Outcome) -> (Vec<String>, bool) {
+        let Outcome::Mate { moves, unique } = outcome else {
+            panic!("{:?}", outcome);
+        };
+        (moves.iter().map(|mv| mv.to_usi_owned()).collect(), unique)
+    }
+
+    #[test]
+    fn shortest_helpmates_are_found() {
+        let (moves, unique) = usi(solve("4k4/9/4P4/9/9/9/9/9/9 b G 1", 7));
+        assert_eq!(moves, ["G*5b"]);
+        assert!(unique);
+        // 玉方が協力しないと詰まない (普通の詰将棋としては不詰)。
+        let sfen = "6k2/9/6s2/7G1/9/9/9/9/9 b P 1";
+        let position = PartialPosition::from_usi(&format!("sfen {}", sfen)).unwrap();
+        assert_eq!(
+            crate::df_pn::search::df_pn(
+                &crate::tt::DfPnTable::new(1 << 10),
+                &PositionWrapper::new(position),
+                false
+            ),
+            (u32::MAX, 0)
+        );
+        let (moves, unique) = usi(solve(sfen, 7));
+        assert_eq!(moves, ["P*3b", "3a3b", "2d3c", "3b3a", "S*3b"]);
+        assert!(unique);
+        // 玉方が自分の駒で逃げ道をふさぐ。同じ手数の手順がほかにもある。
+        let (moves, unique) = usi(solve("3gkg3/9/9/9/9/9/9/9/9 b S 1", 7));
+        assert_eq!(moves.len(), 7);
+        assert!(!unique);
+        assert_eq!(solve("4k4/9/9/9/9/9/9/9/9 b G 1", 7), Outcome::NoMate);
+    }
+
+    #[test]
+    fn search_stops_at_limits() {
+        let position = PartialPosition::from_usi("sfen 3gkg3/9/9/9/9/9/9/9/9 b S 1").unwrap();
+        let limits = SearchLimits {
+            max_nodes: Some(10),
+            ..Default::default()
+        };
+        let mut stats = SearchStats::default();
+        assert_eq!(
+            search_with_limits(&position, 7, &mut stats, &limits),
+            Outcome::Unknown
+        );
+        assert_eq!(stats.positions_inspected, 10);
+    }
+
+    #[test]
+    fn matches_brute_force_on_random_positions() {
+        use rand::{Rng, SeedableRng};
+        use shogi_core::{Color, Piece, PieceKind, Square};
+
+        let mut rng = rand_chacha::ChaCha8Rng::seed_from_u64(1);
+        let piece_kinds = [
+            PieceKind::Pawn,
+            PieceKind::Lance,
+            PieceKind::Knight,
+            PieceKind::Silver,
+            PieceKind::Gold,
+            PieceKind::Bishop,
+            PieceKind::Rook,
+        ];
+        let mut solved = 0;
+        for _ in 0..100 {
+            let mut position = PartialPosition::empty();
+            let king = Square::new(rng.random_range(1..=9), rng.random_range(1..=3)).unwrap();
+            position.piece_set(king, Some(Piece::new(PieceKind::King, Color::White)));
+            for index in 0..rng.random_range(2..=4) {
+                let color = if index % 2 == 0 {
+                    Color::Black
+                } else {
+                    Color::White
+                };
+                let square = Square::new(rng.random_range(1..=9), rng.random_range(1..=5)).unwrap();
+                if position.piece_at(square).is_none() {
+                    let piece_kind = piece_kinds[rng.random_range(0..7)];
+                    position.piece_set(square, Some(Piece::new(piece_kind, color)));
+                }
+            }
+            let hand = position.hand_of_a_player_mut(Color::Black);
+            *hand = hand.added(piece_kinds[rng.random_range(0..7)]).unwrap();
+            if crate::validate::validate(&position).is_err() {
+                continue;
+            }
+            let sfen = position.to_sfen_owned();
+            let outcome = search(&position, 5);
+            let mut position = PositionWrapper::new(position);
+            let counts: Vec<_> = [1, 3, 5]
+                .into_iter()
+                .map(|plies| count_brute_force(&mut position, plies, true))
+                .collect();
+            match (outcome, counts.iter().position(|&count| count > 0)) {
+                (Outcome::NoMate, None) => {}
+                (Outcome::Mate { moves, unique }, Some(index)) => {
+                    assert_eq!(moves.len(), 2 * index + 1, "{}", sfen);
+                    assert_eq!(unique, counts[index] == 1, "{}", sfen);
+                    solved += 1;
+                }
+                (outcome, _) => panic!("{} {:?} {:?}", sfen, outcome, counts),
+            }
+        }
+        assert!(solved > 0);
+    }
+}
@@ -0,0 +1,147 @@
+//! df-pn で初めて訪れた局面の証明数・反証数の初期値。
+use shogi_core::{Move, Square};
+
+use crate::{df_pn::search::NodeKind, position_wrapper::PositionWrapper};
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct LeafInitOptions {
+    pub mode: LeafInitMode,
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum LeafInitMode {
+    /// すべて (1, 1) とする。
+    #[default]
+    Uniform,
+    /// df-pn+ のように、王手に対する応手の数や玉の逃げ道の数から見積もる。
+    Mobility,
+}
+
+impl LeafInitMode {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "uniform" => Some(Self::Uniform),
+            "mobility" => Some(Self::Mobility),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Uniform => "uniform",
+            Self::Mobility => "mobility",
+        }
+    }
+}
+
+/// position で mv を指した後の局面 child の (phi, delta) の初期値を返す。
+///
+/// 値は child の手番側から見たもので、child_kind は child で手番を持つ側である。
+pub fn initial_value(
+    position: &PositionWrapper,
+    mv: Move,
+    child: &PositionWrapper,
+    child_kind: NodeKind,
+    options: &LeafInitOptions,
+) -> (u32, u32) {
+    match options.mode {
+        LeafInitMode::Uniform => (1, 1),
+        LeafInitMode::Mobility => match child_kind {
+            // 王手をかけた後の局面。応手が多いほど詰ませにくい。
+            NodeKind::And => {
+                let evasions = child.all_evasions().len() as u32;
+                (1, evasions.max(1))
+            }
+            // 王手を逃れた後の局面。玉の逃げ道が多いほど、また駒を取って逃れたほど詰ませにくい。
+            NodeKind::Or => {
+                let captured = match mv {
+                    Move::Normal { to, .. } => position.inner().piece_at(to).is_some(),
+                    Move::Drop { .. } => false,
+                };
+                (king_escapes(child) + captured as u32 + 1, 1)
+            }
+            // 自玉詰では玉の逃げ道の多さが手掛かりにならない。
+            NodeKind::SelfOr | NodeKind::SelfAnd => (1, 1),
+        },
+    }
+}
+
+// 手番でない側 (玉方) の玉の周りで、自分の駒がいないマスの数。
+fn king_escapes(position: &PositionWrapper) -> u32 {
+    let inner = position.inner();
+    let defender = inner.side_to_move().flip();
+    let Some(king) = inner.king_position(defender) else {
+        return 0;
+    };
+    let mut count = 0;
+    for file in king.file().saturating_sub(1)..=king.file() + 1 {
+        for rank in king.rank().saturating_sub(1)..=king.rank() + 1 {
+            let Some(square) = Square::new(file, rank) else {
+                continue;
+            };
+            if square == king {
+                continue;
+            }
+            if inner
+                .piece_at(square)
+                .is_none_or(|piece| piece.color() != defender)
+            {
+                count += 1;
+            }
+        }
+    }
+    count
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shogi_core::{PartialPosition, Piece};
+    use shogi_usi_parser::FromUsi;
+
+    #[test]
+    fn mobility_counts_evasions_and_escapes() {
+        let position = PositionWrapper::new(
+            PartialPosition::from_usi("sfen 8k/9/9/9/9/9/9/9/9 b G 1").unwrap(),
+        );
+        let mv = Move::Drop {
+            piece: Piece::B_G,
+            to: Square::SQ_1B,
+        };
+        let mut child = position.clone();
+        child.make_move(mv);
+        let options = LeafInitOptions {
+            mode: LeafInitMode::Mobility,
+        };
+
+        assert_eq!(
+            initial_value(
+                &position,
+                mv,
+                &child,
+                NodeKind::And,
+                &LeafInitOptions::default()
+            ),
+            (1, 1)
+        );
+        // 玉は 1 二の金を取るしかない。
+        assert_eq!(
+            initial_value(&position, mv, &child, NodeKind::And, &options),
+            (1, 1)
+        );
+
+        let capture = Move::Normal {
+            from: Square::SQ_1A,
+            to: Square::SQ_1B,
+            promote: false,
+        };
+        let mut grandchild = child.clone();
+        grandchild.make_move(capture);
+        // 1 二の玉の周りの 5 マスが空いていて、金を取っている。
+        assert_eq!(
+            initial_value(&child, capture, &grandchild, NodeKind::Or, &options),
+            (7, 1)
+        );
+    }
+}
@@ -1,16 +1,27 @@
-use std::collections::{BTreeSet, HashMap};
+use std::{
+    collections::{BTreeSet, HashMap},
+    time::Duration,
+};
 
 use df_pn::search as dfpnsearch;
 use eval::{search as evalsearch, Value};
 use position_wrapper::PositionWrapper;
 use shogi_core::{Move, PartialPosition};
-use tt::{DfPnTable, EvalTable};
+use tt::{DfPnTable, EvalTable, TtStats};
 
 pub mod df_pn;
 pub mod eval;
+pub mod hand;
+pub mod helpmate;
+pub mod leaf_init;
 pub mod move_ordering;
+pub mod movegen;
 pub mod position_wrapper;
+pub mod selfmate;
+pub mod snapshot;
+pub mod threat;
 pub mod tt;
+pub mod validate;
 
 #[derive(Clone, Debug)]
 pub struct Answer {
@@ -30,9 +41,13 @@ impl SearchStats {
         SearchStats {
             df_pn: DfPnStats {
                 positions_inspected: df_pn.positions_inspected,
+                hashfull: df_pn.hashfull,
+                tt: df_pn.tt,
             },
             eval: EvalStats {
                 positions_inspected: eval.positions_inspected,
+                hashfull: eval.hashfull,
+                tt: eval.tt,
             },
         }
     }
@@ -41,11 +56,19 @@ impl SearchStats {
 #[derive(Clone, Copy, Debug, Default)]
 pub struct DfPnStats {
     pub positions_inspected: u64,
+    /// 置換表の使用率 (千分率)
+    pub hashfull: u32,
+    /// 置換表の利用状況
+    pub tt: TtStats,
 }
 
 #[derive(Clone, Copy, Debug, Default)]
 pub struct EvalStats {
     pub positions_inspected: u64,
+    /// 置換表の使用率 (千分率)
+    pub hashfull: u32,
+    /// 置換表の利用状況
+    pub tt: TtStats,
 }
 
 #[derive(Clone, Debug)]
@@ -99,25 +122,40 @@ pub struct Options {
     pub verbose: bool,
 }
 
-// Returns true if the branch is worth recording.
+// Returns true if the branch is worth recording, or None if the limits are reached.
+#[allow(clippy::too_many_arguments)]
 fn find_branches(
     df_pn: &mut DfPnTable,
     evals: &mut EvalTable,
     position: &PositionWrapper,
     opt: Value,
     opts: &Options,
+    limits: &dfpnsearch::SearchLimits,
     memo: &mut HashMap<Vec<Move>, BranchEntry>,
     current: Vec<Move>,
-) -> bool {
+) -> Option<bool> {
     let turn = current.len();
     if turn > opt.plies() as usize {
-        return false;
+        return Some(false);
     }
-    if turn % 2 == 1 && dfpnsearch::df_pn(df_pn, position, opts.verbose) != (u32::MAX, 0) {
-        return false;
+    if turn % 2 == 1 {
+        let outcome = dfpnsearch::df_pn_with_limits(
+            df_pn,
+            position,
+            opts.verbose,
+            &mut Default::default(),
+            &Default::default(),
+            &Default::default(),
+            limits,
+        );
+        match outcome {
+            dfpnsearch::Outcome::NoMate => {}
+            dfpnsearch::Outcome::Mate => return Some(false),
+            dfpnsearch::Outcome::Unknown(_) => return None,
+        }
     }
     let beta = opt.plies_added_unchecked(turn as i32);
-    let mut ctx = evalsearch::SearchCtx::default();
+    let mut ctx = evalsearch::SearchCtx::with_limits(limits.clone());
     let (value, mv) = if turn.is_multiple_of(2) {
         evalsearch::alpha_beta_me(
             position,
@@ -141,8 +179,15 @@ fn find_branches(
             opts.verbose,
         )
     };
+    if ctx.is_stopped() {
+        return None;
+    }
     let all_moves = if turn.is_multiple_of(2) {
-        let mv = if let Some(mv) = mv { mv } else { return false };
+        let mv = if let Some(mv) = mv {
+            mv
+        } else {
+            return Some(false);
+        };
         vec![mv]
     } else {
         let mut tmp = position.all_evasions();
@@ -160,7 +205,7 @@ fn find_branches(
         next.push(mv);
         let mut next_position = position.clone();
         next_position.make_move(mv);
-        if find_branches(df_pn, evals, &next_position, opt, opts, memo, next) {
+        if find_branches(df_pn, evals, &next_position, opt, opts, limits, memo, next)? {
             possible_next_moves.push(mv);
         }
     }
@@ -171,27 +216,57 @@ fn find_branches(
         eval: Some(eval),
     };
     memo.insert(current, branch_entry);
-    true
+    Some(true)
 }
 
-pub fn search(position: &PartialPosition, _timeout_ms: u64) -> Answer {
-    // TODO: use wasm-timer
+// df-pn が timeout_ms ミリ秒以内に終わらなければ Resolution::Unknown を返す。
+// 詰将棋の局面として正しくなければ Resolution::Invalid を返す。
+pub fn search(position: &PartialPosition, timeout_ms: u64) -> Answer {
+    if let Err(error) = validate::validate(position) {
+        return Answer {
+            inner: Err(error),
+            stats: SearchStats::default(),
+            elapsed: 0.0,
+        };
+    }
     let verbose = true;
-    let size = 1 << 16;
-
-    let mut df_pn = DfPnTable::new(size);
-
-    let mut eval = EvalTable::new(size);
+    let mut df_pn = DfPnTable::with_megabytes(tt::DEFAULT_HASH_MB);
+    let mut eval = EvalTable::with_megabytes(tt::DEFAULT_HASH_MB);
     let mut df_pn_stats = dfpnsearch::SearchStats::default();
     let mut eval_stats = evalsearch::SearchStats::default();
-    let mate_result = dfpnsearch::df_pn_with_stats(
-        &mut df_pn,
+    // 時刻が表せないほど先なら時間では打ち切らない。
+    #[cfg(not(target_arch = "wasm32"))]
+    let deadline = std::time::Instant::now().checked_add(Duration::from_millis(timeout_ms));
+    // wasm32 では Instant::now() が使えないので、時間では打ち切らない。
+    // TODO: use wasm-timer
+    #[cfg(target_arch = "wasm32")]
+    let deadline = None;
+    let limits = dfpnsearch::SearchLimits {
+        deadline,
+        ..Default::default()
+    };
+    let unknown = |df_pn_stats, eval_stats| Answer {
+        inner: Err(ErrType {
+            resolution: Resolution::Unknown,
+            reason: format!("search did not finish in {} ms", timeout_ms),
+        }),
+        stats: SearchStats::from_internal(df_pn_stats, eval_stats),
+        elapsed: 0.0,
+    };
+    let mate_result = dfpnsearch::df_pn_with_limits(
+        &df_pn,
         &position_wrapper::PositionWrapper::new(position.clone()),
         verbose,
         &mut df_pn_stats,
+        &Default::default(),
+        &Default::default(),
+        &limits,
     );
+    if let dfpnsearch::Outcome::Unknown(_) = mate_result {
+        return unknown(df_pn_stats, eval_stats);
+    }
     // 不詰。
-    if mate_result == (u32::MAX, 0) {
+    if mate_result == dfpnsearch::Outcome::NoMate {
         return Answer {
             inner: Ok(OkType {
                 resolution: Resolution::NoMate,
@@ -201,14 +276,19 @@ pub fn search(position: &PartialPosition, _timeout_ms: u64) -> Answer {
             elapsed: 0.0,
         };
     }
-    let result = evalsearch::search_with_stats(
+    let result = evalsearch::search_with_limits(
         position,
         &mut df_pn,
         &mut eval,
         verbose,
         &mut eval_stats,
         &mut df_pn_stats,
+        &Default::default(),
+        &limits,
     );
+    let Some(result) = result else {
+        return unknown(df_pn_stats, eval_stats);
+    };
     if verbose {
         eprintln!("! result = {:?}", result);
     }
@@ -223,15 +303,20 @@ pub fn search(position: &PartialPosition, _timeout_ms: u64) -> Answer {
         };
     }
     let mut branches_hashmap = HashMap::new();
-    find_branches(
+    if find_branches(
         &mut df_pn,
         &mut eval,
         &position_wrapper::PositionWrapper::new(position.clone()),
         result,
         &Options { verbose },
+        &limits,
         &mut branches_hashmap,
         vec![],
-    );
+    )
+    .is_none()
+    {
+        return unknown(df_pn_stats, eval_stats);
+    }
     let elapsed = 0.0;
     let mut branches = vec![];
     for branch_entry in branches_hashmap.values() {
@@ -31,10 +31,8 @@ pub fn order_eval_moves(
 ) {
     match options.mode {
         MoveOrderingMode::Current => moves.sort_unstable_by_key(|&mv| {
-            let mut cp = position.clone();
-            cp.make_move(mv);
-            if let Some((_, delta)) = df_pn.fetch(cp.zobrist_hash()) {
-                delta
+            if let Some(entry) = df_pn.fetch(&position.df_pn_key_after(mv)) {
+                entry.delta
             } else {
                 1
             }
@@ -84,9 +82,9 @@ mod tests {
         let mut second_position = position.clone();
         second_position.make_move(second);
 
-        let mut df_pn = DfPnTable::new(16);
-        df_pn.insert(first_position.zobrist_hash(), (1, 8));
-        df_pn.insert(second_position.zobrist_hash(), (1, 2));
+        let df_pn = DfPnTable::new(16);
+        df_pn.insert(&first_position.df_pn_key(), (1, 8));
+        df_pn.insert(&second_position.df_pn_key(), (1, 2));
         let mut moves = [first, second];
 
         order_eval_moves(
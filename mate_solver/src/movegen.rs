@@ -0,0 +1,611 @@
+//! ビットボードと利きの表による指し手生成。
+//!
+//! `shogi_legality_lite` と同じ手を同じ順に返すが、局面を複製せずに利きだけで合法性を判定する。
+//! `native-movegen` feature を有効にすると `PositionWrapper` がこちらを使う。
+
+use once_cell::sync::Lazy;
+use shogi_core::{Bitboard, Color, Hand, Move, PartialPosition, Piece, PieceKind, Square};
+
+// 方向は (筋の差, 段の差) で、先手から見たもの。先手の前は段の減る向き。
+const ORTHOGONAL: [(i8, i8); 4] = [(0, -1), (-1, 0), (1, 0), (0, 1)];
+const DIAGONAL: [(i8, i8); 4] = [(-1, -1), (1, -1), (-1, 1), (1, 1)];
+
+struct Tables {
+    /// 駒の種類・手番・位置ごとの、飛び利き以外の利き。
+    step: [[[Bitboard; Square::NUM]; Color::NUM]; PieceKind::NUM],
+    /// 2 マスの間のマス。同じ直線上になければ空。
+    between: [[Bitboard; Square::NUM]; Square::NUM],
+    /// 筋ごとのマス。
+    files: [Bitboard; 9],
+}
+
+static TABLES: Lazy<Box<Tables>> = Lazy::new(|| {
+    let mut tables = Box::new(Tables {
+        step: [[[Bitboard::empty(); Square::NUM]; Color::NUM]; PieceKind::NUM],
+        between: [[Bitboard::empty(); Square::NUM]; Square::NUM],
+        files: [Bitboard::empty(); 9],
+    });
+    let gold: &[(i8, i8)] = &[(0, -1), (-1, -1), (1, -1), (-1, 0), (1, 0), (0, 1)];
+    let king: &[(i8, i8)] = &[
+        (0, -1),
+        (-1, -1),
+        (1, -1),
+        (-1, 0),
+        (1, 0),
+        (-1, 1),
+        (0, 1),
+        (1, 1),
+    ];
+    for piece_kind in PieceKind::all() {
+        let deltas: &[(i8, i8)] = match piece_kind {
+            PieceKind::Pawn => &[(0, -1)],
+            PieceKind::Knight => &[(-1, -2), (1, -2)],
+            PieceKind::Silver => &[(0, -1), (-1, -1), (1, -1), (-1, 1), (1, 1)],
+            PieceKind::Gold
+            | PieceKind::ProPawn
+            | PieceKind::ProLance
+            | PieceKind::ProKnight
+            | PieceKind::ProSilver => gold,
+            PieceKind::King => king,
+            PieceKind::ProBishop => &ORTHOGONAL,
+            PieceKind::ProRook => &DIAGONAL,
+            PieceKind::Lance | PieceKind::Bishop | PieceKind::Rook => &[],
+        };
+        for color in Color::all() {
+            for from in Square::all() {
+                let mut bb = Bitboard::empty();
+                for &delta in deltas {
+                    if let Some(to) = shift(from, delta, color) {
+                        bb |= to;
+                    }
+                }
+                tables.step[piece_kind.array_index()][color.array_index()][from.array_index()] = bb;
+            }
+        }
+    }
+    for from in Square::all() {
+        for &delta in ORTHOGONAL.iter().chain(&DIAGONAL) {
+            let mut passed = Bitboard::empty();
+            let mut current = from;
+            while let Some(next) = shift(current, delta, Color::Black) {
+                tables.between[from.array_index()][next.array_index()] = passed;
+                passed |= next;
+                current = next;
+            }
+        }
+        tables.files[from.file() as usize - 1] |= from;
+    }
+    tables
+});
+
+#[inline(always)]
+fn shift(square: Square, (file, rank): (i8, i8), color: Color) -> Option<Square> {
+    match color {
+        Color::Black => square.shift(file, rank),
+        Color::White => square.shift(-file, -rank),
+    }
+}
+
+// from から directions の向きに、駒に当たるまで進んだマス。当たったマスも含む。
+#[inline]
+fn slide(from: Square, directions: &[(i8, i8)], color: Color, occupied: Bitboard) -> Bitboard {
+    let mut bb = Bitboard::empty();
+    for &delta in directions {
+        let mut current = from;
+        while let Some(next) = shift(current, delta, color) {
+            bb |= next;
+            if occupied.contains(next) {
+                break;
+            }
+            current = next;
+        }
+    }
+    bb
+}
+
+/// from にある piece の利き。
+#[inline]
+pub fn attacks(piece: Piece, from: Square, occupied: Bitboard) -> Bitboard {
+    let color = piece.color();
+    let step =
+        TABLES.step[piece.piece_kind().array_index()][color.array_index()][from.array_index()];
+    match piece.piece_kind() {
+        PieceKind::Lance => slide(from, &ORTHOGONAL[..1], color, occupied),
+        PieceKind::Bishop | PieceKind::ProBishop => step | slide(from, &DIAGONAL, color, occupied),
+        PieceKind::Rook | PieceKind::ProRook => step | slide(from, &ORTHOGONAL, color, occupied),
+        _ => step,
+    }
+}
+
+/// by の駒のうち target に利いているもの。盤上の駒は occupied とみなす。
+pub fn attackers_to(
+    position: &PartialPosition,
+    target: Square,
+    by: Color,
+    occupied: Bitboard,
+) -> Bitboard {
+    let tables = &*TABLES;
+    // by の駒が target に利く位置は、target にある相手の駒の利きと同じ。
+    let opponent = by.flip();
+    let step = |piece_kind: PieceKind| {
+        tables.step[piece_kind.array_index()][opponent.array_index()][target.array_index()]
+    };
+    let pieces = |piece_kind: PieceKind| position.piece_bitboard(Piece::new(piece_kind, by));
+    let golds = pieces(PieceKind::Gold)
+        | pieces(PieceKind::ProPawn)
+        | pieces(PieceKind::ProLance)
+        | pieces(PieceKind::ProKnight)
+        | pieces(PieceKind::ProSilver);
+    let kings = pieces(PieceKind::King) | pieces(PieceKind::ProBishop) | pieces(PieceKind::ProRook);
+    let mut result = (step(PieceKind::Pawn) & pieces(PieceKind::Pawn))
+        | (step(PieceKind::Knight) & pieces(PieceKind::Knight))
+        | (step(PieceKind::Silver) & pieces(PieceKind::Silver))
+        | (step(PieceKind::Gold) & golds)
+        | (step(PieceKind::King) & kings);
+    let lances = pieces(PieceKind::Lance);
+    if !lances.is_empty() {
+        result |= slide(target, &ORTHOGONAL[..1], opponent, occupied) & lances;
+    }
+    let diagonal = pieces(PieceKind::Bishop) | pieces(PieceKind::ProBishop);
+    if !diagonal.is_empty() {
+        result |= slide(target, &DIAGONAL, opponent, occupied) & diagonal;
+    }
+    let orthogonal = pieces(PieceKind::Rook) | pieces(PieceKind::ProRook);
+    if !orthogonal.is_empty() {
+        result |= slide(target, &ORTHOGONAL, opponent, occupied) & orthogonal;
+    }
+    result
+}
+
+// by の飛び駒と target の間にただ 1 つある駒。手番は問わない。
+fn blockers(position: &PartialPosition, target: Square, by: Color, occupied: Bitboard) -> Bitboard {
+    let opponent = by.flip();
+    let pieces = |piece_kind: PieceKind| position.piece_bitboard(Piece::new(piece_kind, by));
+    let snipers = (slide(target, &ORTHOGONAL[..1], opponent, Bitboard::empty())
+        & pieces(PieceKind::Lance))
+        | (slide(target, &DIAGONAL, opponent, Bitboard::empty())
+            & (pieces(PieceKind::Bishop) | pieces(PieceKind::ProBishop)))
+        | (slide(target, &ORTHOGONAL, opponent, Bitboard::empty())
+            & (pieces(PieceKind::Rook) | pieces(PieceKind::ProRook)));
+    let mut result = Bitboard::empty();
+    for sniper in snipers {
+        let between = TABLES.between[sniper.array_index()][target.array_index()] & occupied;
+        if between.count() == 1 {
+            result |= between;
+        }
+    }
+    result
+}
+
+// 行き所のない駒にならないか。
+#[inline]
+fn can_stay(piece_kind: PieceKind, to: Square, side: Color) -> bool {
+    match piece_kind {
+        PieceKind::Pawn | PieceKind::Lance => to.relative_rank(side) > 1,
+        PieceKind::Knight => to.relative_rank(side) > 2,
+        _ => true,
+    }
+}
+
+// 手番側から見た局面の情報。
+struct Context<'a> {
+    position: &'a PartialPosition,
+    side: Color,
+    occupied: Bitboard,
+    mine: Bitboard,
+    /// 手番側の玉
+    king: Option<Square>,
+    /// 手番側の玉に王手をかけている駒
+    checkers: Bitboard,
+    /// 動くと手番側の玉に王手がかかりうる駒
+    pinned: Bitboard,
+    /// 相手の玉のマス。取る手は生成しない。
+    their_king: Bitboard,
+}
+
+impl<'a> Context<'a> {
+    fn new(position: &'a PartialPosition) -> Self {
+        let side = position.side_to_move();
+        let occupied = position.occupied_bitboard();
+        let mine = position.player_bitboard(side);
+        let king = position.king_position(side);
+        let (checkers, pinned) = match king {
+            Some(king) => (
+                attackers_to(position, king, side.flip(), occupied),
+                blockers(position, king, side.flip(), occupied) & mine,
+            ),
+            None => (Bitboard::empty(), Bitboard::empty()),
+        };
+        let their_king = position
+            .king_position(side.flip())
+            .map_or(Bitboard::empty(), Bitboard::single);
+        Self {
+            position,
+            side,
+            occupied,
+            mine,
+            king,
+            checkers,
+            pinned,
+            their_king,
+        }
+    }
+
+    // 玉以外の駒が動ける先、駒を打てる先。王手をかけられていれば、その駒を取るか合駒をする手に限る。
+    fn evasion_mask(&self) -> Bitboard {
+        match self.checkers.count() {
+            0 => !Bitboard::empty(),
+            1 => {
+                let checker = self.checkers.into_iter().next().unwrap();
+                let king = self.king.unwrap();
+                self.checkers | TABLES.between[checker.array_index()][king.array_index()]
+            }
+            _ => Bitboard::empty(),
+        }
+    }
+
+    // from から to へ動いた後、手番側の玉に利きがないか。
+    #[inline]
+    fn is_safe_after_normal(&self, piece_kind: PieceKind, from: Square, to: Square) -> bool {
+        let king = match self.king {
+            Some(king) => king,
+            None => return true,
+        };
+        let occupied = (self.occupied & !Bitboard::single(from)) | Bitboard::single(to);
+        if piece_kind == PieceKind::King {
+            return (attackers_to(self.position, to, self.side.flip(), occupied)
+                & !Bitboard::single(to))
+            .is_empty();
+        }
+        if self.checkers.is_empty() && !self.pinned.contains(from) {
+            return true;
+        }
+        (attackers_to(self.position, king, self.side.flip(), occupied) & !Bitboard::single(to))
+            .is_empty()
+    }
+
+    // to に駒を打った後、手番側の玉に利きがないか。
+    #[inline]
+    fn is_safe_after_drop(&self, to: Square) -> bool {
+        match self.king {
+            Some(king) if !self.checkers.is_empty() => attackers_to(
+                self.position,
+                king,
+                self.side.flip(),
+                self.occupied | Bitboard::single(to),
+            )
+            .is_empty(),
+            _ => true,
+        }
+    }
+
+    // from の駒を mask の中へ動かす合法手。promote ごとに to の順に sink に渡す。
+    // sink が false を返したら打ち切って false を返す。
+    fn normal_moves(
+        &self,
+        from: Square,
+        mask: Bitboard,
+        sink: &mut impl FnMut(Move) -> bool,
+    ) -> bool {
+        let piece = match self.position.piece_at(from) {
+            Some(piece) => piece,
+            None => return true,
+        };
+        let piece_kind = piece.piece_kind();
+        let mut targets = attacks(piece, from, self.occupied) & !self.mine & !self.their_king;
+        if piece_kind != PieceKind::King {
+            targets &= mask;
+        }
+        for to in targets {
+            if can_stay(piece_kind, to, self.side)
+                && self.is_safe_after_normal(piece_kind, from, to)
+                && !sink(Move::Normal {
+                    from,
+                    to,
+                    promote: false,
+                })
+            {
+                return false;
+            }
+        }
+        if piece_kind.promote().is_none() {
+            return true;
+        }
+        for to in targets {
+            if (from.relative_rank(self.side) <= 3 || to.relative_rank(self.side) <= 3)
+                && self.is_safe_after_normal(piece_kind, from, to)
+                && !sink(Move::Normal {
+                    from,
+                    to,
+                    promote: true,
+                })
+            {
+                return false;
+            }
+        }
+        true
+    }
+
+    // piece_kind を mask の中へ打つ合法手。to の順に sink に渡す。
+    fn drops(
+        &self,
+        piece_kind: PieceKind,
+        mask: Bitboard,
+        sink: &mut impl FnMut(Move) -> bool,
+    ) -> bool {
+        let hand = self.position.hand_of_a_player(self.side);
+        if hand.count(piece_kind).unwrap_or(0) == 0 {
+            return true;
+        }
+        let piece = Piece::new(piece_kind, self.side);
+        let mut targets = !self.occupied & mask;
+        if piece_kind == PieceKind::Pawn {
+            for pawn in self.position.piece_bitboard(piece) {
+                targets &= !TABLES.files[pawn.file() as usize - 1];
+            }
+        }
+        for to in targets {
+            if !can_stay(piece_kind, to, self.side) || !self.is_safe_after_drop(to) {
+                continue;
+            }
+            if piece_kind == PieceKind::Pawn && is_pawn_drop_mate(self.position, to) {
+                continue;
+            }
+            if !sink(Move::Drop { piece, to }) {
+                return false;
+            }
+        }
+        true
+    }
+
+    fn legal_moves(&self, sink: &mut impl FnMut(Move) -> bool) -> bool {
+        let mask = self.evasion_mask();
+        for from in self.mine {
+            if !self.normal_moves(from, mask, sink) {
+                return false;
+            }
+        }
+        Hand::all_hand_pieces()
+            .into_iter()
+            .all(|piece_kind| self.drops(piece_kind, mask, sink))
+    }
+}
+
+// 手番側が to に歩を打つと打ち歩詰めになるか。
+fn is_pawn_drop_mate(position: &PartialPosition, to: Square) -> bool {
+    let side = position.side_to_move();
+    match position.king_position(side.flip()) {
+        Some(king) if shift(to, ORTHOGONAL[0], side) == Some(king) => {}
+        _ => return false,
+    }
+    let mut next = position.clone();
+    let result = next.make_move(Move::Drop {
+        piece: Piece::new(PieceKind::Pawn, side),
+        to,
+    });
+    debug_assert!(result.is_some());
+    !has_legal_move(&next)
+}
+
+/// 手番側の合法手をすべて返す。`shogi_legality_lite::all_legal_moves_partial` と同じ順に並べる。
+pub fn all_legal_moves(position: &PartialPosition) -> Vec<Move> {
+    let mut moves = vec![];
+    Context::new(position).legal_moves(&mut |mv| {
+        moves.push(mv);
+        true
+    });
+    moves
+}
+
+/// 手番側に合法手があるか。
+pub fn has_legal_move(position: &PartialPosition) -> bool {
+    let ctx = Context::new(position);
+    let mut found = |_| false;
+    // 玉が逃げられることが多いので、玉の手から調べる。
+    if let Some(king) = ctx.king {
+        if !ctx.normal_moves(king, Bitboard::empty(), &mut found) {
+            return true;
+        }
+    }
+    let mask = ctx.evasion_mask();
+    if mask.is_empty() {
+        return false;
+    }
+    for from in ctx.mine {
+        if Some(from) != ctx.king && !ctx.normal_moves(from, mask, &mut found) {
+            return true;
+        }
+    }
+    !Hand::all_hand_pieces()
+        .into_iter()
+        .all(|piece_kind| ctx.drops(piece_kind, mask, &mut found))
+}
+
+/// 手番側の合法な王手をすべて返す。
+///
+/// 成る手を先に、それぞれ `shogi_legality_lite::all_checks_partial` と同じ順に並べる。
+/// 直接の王手は玉に利くマスへの手だけを、開き王手は玉との間に 1 枚だけある駒の手だけを調べる。
+pub fn all_checks(position: &PartialPosition) -> Vec<Move> {
+    let ctx = Context::new(position);
+    let side = ctx.side;
+    let king = match position.king_position(side.flip()) {
+        Some(king) => king,
+        None => return vec![],
+    };
+    let mask = ctx.evasion_mask();
+    let discoverers = blockers(position, king, side, ctx.occupied) & ctx.mine;
+    let mut promoting = vec![];
+    let mut others = vec![];
+    for from in ctx.mine {
+        let piece = position.piece_at(from).unwrap();
+        let piece_kind = piece.piece_kind();
+        let is_discoverer = discoverers.contains(from);
+        let mut mask = mask;
+        if !is_discoverer {
+            // 動いた駒が玉に利くマス。
+            let occupied = ctx.occupied & !Bitboard::single(from);
+            let mut near = attacks(Piece::new(piece_kind, side.flip()), king, occupied);
+            if let Some(promoted) = piece_kind.promote() {
+                near |= attacks(Piece::new(promoted, side.flip()), king, occupied);
+            }
+            mask &= near;
+        }
+        ctx.normal_moves(from, mask, &mut |mv| {
+            let (to, promote) = match mv {
+                Move::Normal { to, promote, .. } => (to, promote),
+                Move::Drop { .. } => unreachable!(),
+            };
+            let occupied = (ctx.occupied & !Bitboard::single(from)) | Bitboard::single(to);
+            let moved = if promote {
+                piece.promote().unwrap()
+            } else {
+                piece
+            };
+            let check = attacks(moved, to, occupied).contains(king)
+                || (is_discoverer
+                    && !(attackers_to(position, king, side, occupied) & !Bitboard::single(from))
+                        .is_empty());
+            if check {
+                if promote {
+                    promoting.push(mv);
+                } else {
+                    others.push(mv);
+                }
+            }
+            true
+        });
+    }
+    for piece_kind in Hand::all_hand_pieces() {
+        let near = attacks(Piece::new(piece_kind, side.flip()), king, ctx.occupied);
+        ctx.drops(piece_kind, near & mask, &mut |mv| {
+            others.push(mv);
+            true
+        });
+    }
+    promoting.append(&mut others);
+    promoting
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{seq::IndexedRandom, SeedableRng};
+    use shogi_core::ToUsi;
+    use shogi_usi_parser::FromUsi;
+
+    const SFENS: [&str; 7] = [
+        "sfen lnsgkgsnl/1r5b1/ppppppppp/9/9/9/PPPPPPPPP/1B5R1/LNSGKGSNL b - 1",
+        "sfen 3g1ks2/6g2/4S4/7B1/9/9/9/9/9 b G2rbg2s4n4l18p 1",
+        "sfen 5kgnl/9/4+B1pp1/8p/9/9/9/9/9 b 2S2rb3g2s3n3l15p 1",
+        "sfen 7nk/9/6PB1/6NP1/9/9/9/9/9 b P2rb4g4s2n4l15p 1",
+        "sfen 8k/9/8P/9/9/9/9/9/9 b G2r2b3g4s4n4l17p 1",
+        "sfen 8k/9/9/9/9/9/9/9/9 b Rr2b4g4s4n4l18p 1",
+        "sfen 4k4/9/4P4/9/4L4/9/9/9/4K4 b RBGSNLrb3g3s3n2l17p 1",
+    ];
+
+    fn sorted(mut moves: Vec<Move>) -> Vec<String> {
+        let mut result: Vec<_> = moves.drain(..).map(|mv| mv.to_usi_owned()).collect();
+        result.sort();
+        result
+    }
+
+    // shogi_legality_lite で求めた合法な王手。
+    fn lite_checks(position: &PartialPosition) -> Vec<Move> {
+        shogi_legality_lite::all_legal_moves_partial(position)
+            .into_iter()
+            .filter(|&mv| {
+                let mut next = position.clone();
+                next.make_move(mv).unwrap();
+                shogi_legality_lite::is_in_check_partial_lite(&next)
+            })
+            .collect()
+    }
+
+    fn assert_same_moves(position: &PartialPosition) {
+        let sfen = position.to_sfen_owned();
+        let legal = shogi_legality_lite::all_legal_moves_partial(position);
+        assert_eq!(all_legal_moves(position), legal, "{}", sfen);
+        assert_eq!(has_legal_move(position), !legal.is_empty(), "{}", sfen);
+        let checks = all_checks(position);
+        assert_eq!(
+            sorted(checks.clone()),
+            sorted(lite_checks(position)),
+            "{}",
+            sfen
+        );
+        if !shogi_legality_lite::is_in_check_partial_lite(position) {
+            // 王手をかけられていなければ all_checks_partial と同じになる。
+            let mut lite = shogi_legality_lite::all_checks_partial(position);
+            lite.sort_by_key(|mv| !mv.is_promoting());
+            assert_eq!(checks, lite, "{}", sfen);
+        }
+    }
+
+    #[test]
+    fn matches_legality_lite_on_random_games() {
+        let mut rng = rand_chacha::ChaCha8Rng::seed_from_u64(18);
+        for sfen in SFENS {
+            for _ in 0..2 {
+                let mut position = PartialPosition::from_usi(sfen).unwrap();
+                for _ in 0..30 {
+                    assert_same_moves(&position);
+                    let moves = all_legal_moves(&position);
+                    let mv = match moves.choose(&mut rng) {
+                        Some(&mv) => mv,
+                        None => break,
+                    };
+                    position.make_move(mv).unwrap();
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn matches_legality_lite_on_check_sequences() {
+        // 王手と応手を交互に指して、詰将棋の探索で現れる局面を調べる。
+        let mut rng = rand_chacha::ChaCha8Rng::seed_from_u64(1018);
+        for sfen in SFENS {
+            for _ in 0..4 {
+                let mut position = PartialPosition::from_usi(sfen).unwrap();
+                for ply in 0..12 {
+                    assert_same_moves(&position);
+                    let moves = if ply % 2 == 0 {
+                        all_checks(&position)
+                    } else {
+                        all_legal_moves(&position)
+                    };
+                    let mv = match moves.choose(&mut rng) {
+                        Some(&mv) => mv,
+                        None => break,
+                    };
+                    position.make_move(mv).unwrap();
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn pawn_drop_mate_is_excluded() {
+        // ▲1二歩は打ち歩詰め。
+        let position = PartialPosition::from_usi("sfen 7lk/7p1/7G1/9/9/9/9/9/9 b P 1").unwrap();
+        let checks = sorted(all_checks(&position));
+        assert_eq!(checks, sorted(lite_checks(&position)));
+        assert!(!checks.contains(&"P*1b".to_string()));
+        assert!(checks.contains(&"2c1b".to_string()));
+        assert!(!sorted(all_legal_moves(&position)).contains(&"P*1b".to_string()));
+        // 金がなければ玉で取れるので打ち歩詰めではない。
+        let position = PartialPosition::from_usi("sfen 7lk/7p1/9/9/9/9/9/9/9 b P 1").unwrap();
+        assert_eq!(sorted(all_checks(&position)), ["P*1b"]);
+    }
+
+    #[test]
+    fn discovered_checks_are_generated() {
+        // 角の前の銀が動くと開き王手になる。
+        let position = PartialPosition::from_usi("sfen 8k/9/6S2/9/4B4/9/9/9/9 b - 1").unwrap();
+        let checks = sorted(all_checks(&position));
+        assert_eq!(checks, sorted(lite_checks(&position)));
+        assert!(checks.contains(&"3c2d".to_string()));
+        assert!(!checks.contains(&"3c4d".to_string()));
+        assert!(checks.contains(&"3c2b+".to_string()));
+    }
+}
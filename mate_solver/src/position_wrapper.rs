@@ -1,117 +1,288 @@
 use once_cell::sync::Lazy;
 use shogi_core::{Color, Hand, Move, PartialPosition, Piece, PieceKind, Square};
 
+use crate::tt::DfPnKey;
+
+/// 局面のハッシュ値。`wide-keys` feature を有効にすると 128 ビットになる。
+#[cfg(not(feature = "wide-keys"))]
 pub type Key = u64;
+/// 局面のハッシュ値。`wide-keys` feature を有効にすると 128 ビットになる。
+#[cfg(feature = "wide-keys")]
+pub type Key = u128;
+
+/// make_move を取り消すための記録。
+#[derive(Clone, Copy, Debug)]
+pub struct Undo {
+    mv: Move,
+    /// 取った駒
+    captured: Option<Piece>,
+    /// 盤面と手番のハッシュ値の差分
+    board_diff: Key,
+    /// 局面のハッシュ値の差分
+    hash_diff: Key,
+    /// 盤面と手番の検査用の値の差分
+    lock_diff: u32,
+}
 
 #[derive(Clone, Debug)]
 pub struct PositionWrapper {
     inner: PartialPosition,
     hash: Key,
+    /// 盤面と手番だけから計算したハッシュ値。持ち駒は含まない。
+    board_hash: Key,
+    /// 盤面と手番を board_hash とは別の乱数で表した検査用の値。board_hash が衝突しても局面を見分けるのに使う。
+    board_lock: u32,
 }
 impl PositionWrapper {
     #[inline]
     pub fn new(position: PartialPosition) -> Self {
-        let hash = Self::compute_hash(&position);
+        let board_hash = Self::compute_board_hash(&position);
+        let hash = board_hash ^ Self::compute_hand_hash(&position);
+        let board_lock = Self::compute_board_lock(&position);
         Self {
             inner: position,
             hash,
+            board_hash,
+            board_lock,
         }
     }
 
-    /// 攻め方の王手の一覧。
+    /// 攻め方の王手の一覧。成る手が先に来る。
+    ///
+    /// 攻め方に玉があれば、自玉に王手がかかったままになる手は含まない。
+    #[cfg(not(feature = "native-movegen"))]
     pub fn all_checks(&self) -> Vec<Move> {
         let mut moves = shogi_legality_lite::all_checks_partial(&self.inner);
+        // all_checks_partial は駒打ちで自玉の王手が外れるかを調べないので、逆王手をかけられているときは除く。
+        if shogi_legality_lite::is_in_check_partial_lite(&self.inner) {
+            moves.retain(|&mv| shogi_legality_lite::is_legal_partial_lite(&self.inner, mv));
+        }
         moves.sort_by_key(|mv| !mv.is_promoting()); // promoting moves come first
         moves
     }
 
+    /// 攻め方の王手の一覧。成る手が先に来る。
+    ///
+    /// 攻め方に玉があれば、自玉に王手がかかったままになる手は含まない。
+    #[cfg(feature = "native-movegen")]
+    #[inline(always)]
+    pub fn all_checks(&self) -> Vec<Move> {
+        crate::movegen::all_checks(&self.inner)
+    }
+
     /// 玉方の手の一覧。
     #[inline(always)]
     pub fn all_evasions(&self) -> Vec<Move> {
-        shogi_legality_lite::all_legal_moves_partial(&self.inner)
+        #[cfg(not(feature = "native-movegen"))]
+        return shogi_legality_lite::all_legal_moves_partial(&self.inner);
+        #[cfg(feature = "native-movegen")]
+        return crate::movegen::all_legal_moves(&self.inner);
+    }
+
+    /// 攻め方の王手 checks のうち、1 手で詰む手を探す。
+    pub fn mate_in_one_among(&self, checks: &[Move]) -> Option<Move> {
+        self.mates_in_one_among(checks).next()
+    }
+
+    /// 攻め方の王手 checks のうち、1 手で詰む手をすべて列挙する。
+    pub fn mates_in_one_among<'a>(&'a self, checks: &'a [Move]) -> impl Iterator<Item = Move> + 'a {
+        // 1 つの局面で指しては戻す。
+        let mut next = self.inner.clone();
+        checks.iter().copied().filter(move |&mv| {
+            let captured = captured_piece(&next, mv);
+            if next.make_move(mv).is_none() {
+                return false;
+            }
+            let mate = !has_legal_move(&next);
+            unmake_partial(&mut next, mv, captured);
+            mate
+        })
+    }
+
+    /// 1 手で詰む手があれば返す。
+    pub fn mate_in_one(&self) -> Option<Move> {
+        self.mate_in_one_among(&self.all_checks())
+    }
+
+    /// 自玉詰の攻め方の王手 checks のうち、玉方がどう応じても攻め方の玉が詰む手を探す。
+    pub fn selfmate_in_one_among(&self, checks: &[Move]) -> Option<Move> {
+        let mut next = self.clone();
+        checks.iter().copied().find(|&mv| {
+            let undo = next.make_move(mv);
+            let replies = next.all_evasions();
+            let selfmate = !replies.is_empty()
+                && replies.iter().all(|&reply| {
+                    let undo = next.make_move(reply);
+                    let mated = shogi_legality_lite::is_in_check_partial_lite(next.inner())
+                        && !has_legal_move(next.inner());
+                    next.unmake_move(undo);
+                    mated
+                });
+            next.unmake_move(undo);
+            selfmate
+        })
     }
 
     /// 局面のハッシュ値。この値は衝突してはならない。
+    ///
+    /// 64 ビットでは数十億局面を調べると衝突しうるので、長い探索では `wide-keys` feature で 128 ビットにする。
     #[inline(always)]
-    pub fn zobrist_hash(&self) -> u64 {
+    pub fn zobrist_hash(&self) -> Key {
         self.hash
     }
 
+    /// 盤面と手番のハッシュ値。持ち駒だけが異なる局面同士では同じ値になる。
+    #[inline(always)]
+    pub fn board_hash(&self) -> Key {
+        self.board_hash
+    }
+
+    /// df-pn の置換表を引くための鍵。
+    #[inline]
+    pub fn df_pn_key(&self) -> DfPnKey {
+        let side = self.inner.side_to_move();
+        DfPnKey {
+            board: self.board_hash,
+            lock: self.board_lock,
+            mine: self.inner.hand_of_a_player(side),
+            yours: self.inner.hand_of_a_player(side.flip()),
+        }
+    }
+
     #[inline(always)]
     pub fn inner(&self) -> &PartialPosition {
         &self.inner
     }
 
-    /// 手を指す。ハッシュ値も更新する。
-    pub fn make_move(&mut self, mv: Move) {
-        let mut diff = 0;
-        if let Move::Normal { to, .. } = mv {
-            let original = self.inner.piece_at(to);
-            if let Some(original) = original {
-                let (piece_kind, color) = original.to_parts();
-                diff = TABLE.board[to.array_index()][color.array_index()][piece_kind.array_index()];
-                let unpromoted = if let Some(x) = piece_kind.unpromote() {
-                    x
-                } else {
-                    piece_kind
-                };
-                let num = self
-                    .inner
-                    .hand(Piece::new(unpromoted, color.flip()))
-                    .unwrap();
-                diff ^=
-                    TABLE.hands[1 - color.array_index()][unpromoted.array_index()][num as usize];
-            }
+    /// 手を指す。ハッシュ値も更新する。戻り値を unmake_move に渡すと元の局面に戻る。
+    pub fn make_move(&mut self, mv: Move) -> Undo {
+        let (board_diff, hand_diff, lock_diff) = self.hash_diff(mv);
+        let captured = captured_piece(&self.inner, mv);
+        if self.inner.make_move(mv).is_none() {
+            unreachable!();
+        }
+        self.board_hash ^= board_diff;
+        self.hash ^= board_diff ^ hand_diff;
+        self.board_lock ^= lock_diff;
+        Undo {
+            mv,
+            captured,
+            board_diff,
+            hash_diff: board_diff ^ hand_diff,
+            lock_diff,
         }
-        if self.inner.make_move(mv).is_some() {
-            match mv {
-                Move::Normal { from, to, promote } => {
-                    let piece = self.inner.piece_at(to);
-                    if let Some(piece) = piece {
-                        let (piece_kind, color) = piece.to_parts();
-                        let before = if promote {
-                            piece_kind.unpromote().unwrap()
-                        } else {
-                            piece_kind
-                        };
-                        diff ^= TABLE.board[to.array_index()][color.array_index()]
-                            [piece_kind.array_index()];
-                        diff ^= TABLE.board[from.array_index()][color.array_index()]
-                            [before.array_index()];
-                    }
+    }
+
+    /// make_move で指した手を取り消す。
+    ///
+    /// 直前の手 (PartialPosition::last_move) だけは元に戻らない。
+    pub fn unmake_move(&mut self, undo: Undo) {
+        unmake_partial(&mut self.inner, undo.mv, undo.captured);
+        self.board_hash ^= undo.board_diff;
+        self.hash ^= undo.hash_diff;
+        self.board_lock ^= undo.lock_diff;
+    }
+
+    /// mv を指した後の局面のハッシュ値。局面は変更しない。
+    #[inline]
+    pub fn hash_after(&self, mv: Move) -> Key {
+        let (board_diff, hand_diff, _) = self.hash_diff(mv);
+        self.hash ^ board_diff ^ hand_diff
+    }
+
+    /// mv を指した後の局面の df-pn の置換表の鍵。局面は変更しない。
+    pub fn df_pn_key_after(&self, mv: Move) -> DfPnKey {
+        let side = self.inner.side_to_move();
+        let (board_diff, _, lock_diff) = self.hash_diff(mv);
+        let mut mover = self.inner.hand_of_a_player(side);
+        match mv {
+            Move::Normal { to, .. } => {
+                if let Some(captured) = self.inner.piece_at(to) {
+                    let piece_kind = captured.piece_kind();
+                    mover = mover
+                        .added(piece_kind.unpromote().unwrap_or(piece_kind))
+                        .unwrap();
                 }
-                Move::Drop { piece, to } => {
-                    let (piece_kind, color) = piece.to_parts();
-                    // 盤上の差分
-                    diff ^= TABLE.board[to.array_index()][color.array_index()]
+            }
+            Move::Drop { piece, .. } => mover = mover.removed(piece.piece_kind()).unwrap(),
+        }
+        // 指した後は相手の手番になる。
+        DfPnKey {
+            board: self.board_hash ^ board_diff,
+            lock: self.board_lock ^ lock_diff,
+            mine: self.inner.hand_of_a_player(side.flip()),
+            yours: mover,
+        }
+    }
+
+    // mv を指したときの (盤面と手番のハッシュ値の差分, 持ち駒のハッシュ値の差分, 盤面と手番の検査用の値の差分)。
+    fn hash_diff(&self, mv: Move) -> (Key, Key, u32) {
+        let side = self.inner.side_to_move();
+        let mut board_diff = TABLE.color;
+        let mut hand_diff = 0;
+        let mut lock_diff = TABLE.color_lock;
+        match mv {
+            Move::Normal { from, to, promote } => {
+                let before = self.inner.piece_at(from).unwrap().piece_kind();
+                let after = if promote {
+                    before.promote().unwrap()
+                } else {
+                    before
+                };
+                board_diff ^=
+                    TABLE.board[from.array_index()][side.array_index()][before.array_index()];
+                board_diff ^=
+                    TABLE.board[to.array_index()][side.array_index()][after.array_index()];
+                lock_diff ^=
+                    TABLE.locks[from.array_index()][side.array_index()][before.array_index()];
+                lock_diff ^= TABLE.locks[to.array_index()][side.array_index()][after.array_index()];
+                if let Some(captured) = self.inner.piece_at(to) {
+                    let (piece_kind, color) = captured.to_parts();
+                    board_diff ^= TABLE.board[to.array_index()][color.array_index()]
+                        [piece_kind.array_index()];
+                    lock_diff ^= TABLE.locks[to.array_index()][color.array_index()]
                         [piece_kind.array_index()];
-                    // 持ち駒の差分
-                    let now = self.inner.hand(piece).unwrap();
-                    diff ^=
-                        TABLE.hands[color.array_index()][piece_kind.array_index()][now as usize];
+                    let unpromoted = piece_kind.unpromote().unwrap_or(piece_kind);
+                    let num = self.inner.hand(Piece::new(unpromoted, side)).unwrap();
+                    hand_diff ^=
+                        TABLE.hands[side.array_index()][unpromoted.array_index()][num as usize];
                 }
             }
-            diff ^= COLOR_HASH;
-            self.hash ^= diff;
-        } else {
-            unreachable!();
+            Move::Drop { piece, to } => {
+                let (piece_kind, color) = piece.to_parts();
+                board_diff ^=
+                    TABLE.board[to.array_index()][color.array_index()][piece_kind.array_index()];
+                lock_diff ^=
+                    TABLE.locks[to.array_index()][color.array_index()][piece_kind.array_index()];
+                let num = self.inner.hand(piece).unwrap();
+                hand_diff ^=
+                    TABLE.hands[color.array_index()][piece_kind.array_index()][num as usize - 1];
+            }
         }
+        (board_diff, hand_diff, lock_diff)
     }
 
-    /// 攻め方 (先手) の持ち駒を相手の駒台に移動させる。ハッシュ値も更新する。
+    /// 手番側の持ち駒を相手の駒台に移動させる。ハッシュ値も更新する。
     pub fn give_piece(&mut self, piece_kind: PieceKind) {
-        let s = self.inner.hand_of_a_player_mut(Color::Black);
+        let side = self.inner.side_to_move();
+        let s = self.inner.hand_of_a_player_mut(side);
         *s = s.removed(piece_kind).unwrap();
-        self.hash ^= TABLE.hands[Color::Black.array_index()][piece_kind.array_index()]
+        self.hash ^= TABLE.hands[side.array_index()][piece_kind.array_index()]
             [s.count(piece_kind).unwrap() as usize];
-        let s = self.inner.hand_of_a_player_mut(Color::White);
-        self.hash ^= TABLE.hands[Color::White.array_index()][piece_kind.array_index()]
+        let s = self.inner.hand_of_a_player_mut(side.flip());
+        self.hash ^= TABLE.hands[side.flip().array_index()][piece_kind.array_index()]
             [s.count(piece_kind).unwrap() as usize];
         *s = s.added(piece_kind).unwrap();
     }
 
     /// 局面のハッシュ値を計算する。
+    #[cfg(test)]
     fn compute_hash(position: &PartialPosition) -> Key {
+        Self::compute_board_hash(position) ^ Self::compute_hand_hash(position)
+    }
+
+    /// 盤面と手番のハッシュ値を計算する。
+    fn compute_board_hash(position: &PartialPosition) -> Key {
         let mut x = 0;
         for i in 0..81 {
             // Safety: i+1 は 1..=81 に含まれる
@@ -124,8 +295,27 @@ impl PositionWrapper {
             }
         }
         if position.side_to_move() == Color::White {
-            x ^= COLOR_HASH;
+            x ^= TABLE.color;
+        }
+        x
+    }
+
+    /// 盤面と手番の検査用の値を計算する。
+    fn compute_board_lock(position: &PartialPosition) -> u32 {
+        let mut x = 0;
+        for square in position.occupied_bitboard() {
+            let (piece_kind, color) = position.piece_at(square).unwrap().to_parts();
+            x ^= TABLE.locks[square.array_index()][color.array_index()][piece_kind.array_index()];
+        }
+        if position.side_to_move() == Color::White {
+            x ^= TABLE.color_lock;
         }
+        x
+    }
+
+    /// 持ち駒のハッシュ値を計算する。
+    fn compute_hand_hash(position: &PartialPosition) -> Key {
+        let mut x = 0;
         for color in Color::all() {
             let hand = position.hand_of_a_player(color);
             for piece_kind in Hand::all_hand_pieces() {
@@ -139,15 +329,102 @@ impl PositionWrapper {
     }
 }
 
+// mv で取られる駒。
+fn captured_piece(position: &PartialPosition, mv: Move) -> Option<Piece> {
+    match mv {
+        Move::Normal { to, .. } => position.piece_at(to),
+        Move::Drop { .. } => None,
+    }
+}
+
+// position で指された mv を取り消す。captured は mv で取られた駒。
+fn unmake_partial(position: &mut PartialPosition, mv: Move, captured: Option<Piece>) {
+    let side = position.side_to_move().flip();
+    match mv {
+        Move::Normal { from, to, promote } => {
+            let piece = position.piece_at(to).unwrap();
+            let before = if promote {
+                piece.unpromote().unwrap()
+            } else {
+                piece
+            };
+            position.piece_set(from, Some(before));
+            position.piece_set(to, captured);
+            if let Some(captured) = captured {
+                let piece_kind = captured.piece_kind();
+                let hand = position.hand_of_a_player_mut(side);
+                *hand = hand
+                    .removed(piece_kind.unpromote().unwrap_or(piece_kind))
+                    .unwrap();
+            }
+        }
+        Move::Drop { piece, to } => {
+            position.piece_set(to, None);
+            let hand = position.hand_of_a_player_mut(side);
+            *hand = hand.added(piece.piece_kind()).unwrap();
+        }
+    }
+    position.side_to_move_set(side);
+    let restored = position.ply_set(position.ply().wrapping_sub(1));
+    debug_assert!(restored);
+}
+
+// 手番側に合法手があるか。all_legal_moves_partial と同じ判定を、1 つ見つかった時点で打ち切る。
+#[cfg(feature = "native-movegen")]
+#[inline(always)]
+fn has_legal_move(position: &PartialPosition) -> bool {
+    crate::movegen::has_legal_move(position)
+}
+
+// 手番側に合法手があるか。all_legal_moves_partial と同じ判定を、1 つ見つかった時点で打ち切る。
+#[cfg(not(feature = "native-movegen"))]
+fn has_legal_move(position: &PartialPosition) -> bool {
+    let side = position.side_to_move();
+    // 玉が逃げられることが多いので、玉の手から調べる。
+    if let Some(king) = position.king_position(side) {
+        if !shogi_legality_lite::normal_from_candidates(position, king).is_empty() {
+            return true;
+        }
+    }
+    for from in position.player_bitboard(side) {
+        if Some(from) != position.king_position(side)
+            && !shogi_legality_lite::normal_from_candidates(position, from).is_empty()
+        {
+            return true;
+        }
+    }
+    let hand = position.hand_of_a_player(side);
+    for piece_kind in Hand::all_hand_pieces() {
+        if hand.count(piece_kind) == Some(0) {
+            continue;
+        }
+        let piece = Piece::new(piece_kind, side);
+        for to in position.vacant_bitboard() {
+            if shogi_legality_lite::is_legal_partial_lite(position, Move::Drop { piece, to }) {
+                return true;
+            }
+        }
+    }
+    false
+}
+
 struct ZobristTable {
-    board: [[[u64; PieceKind::NUM]; Color::NUM]; Square::NUM],
-    hands: [[[u64; 18]; Hand::NUM_HAND_PIECES]; Color::NUM],
+    board: [[[Key; PieceKind::NUM]; Color::NUM]; Square::NUM],
+    hands: [[[Key; 18]; Hand::NUM_HAND_PIECES]; Color::NUM],
+    /// 後手番のときに盤面のハッシュ値に加える値
+    color: Key,
+    /// 盤面の検査用の値。board とは別の乱数を使う。
+    locks: [[[u32; PieceKind::NUM]; Color::NUM]; Square::NUM],
+    color_lock: u32,
 }
 
+/// Zobrist ハッシュの乱数の種。変えると保存した置換表は使えなくなる。
+pub const ZOBRIST_SEED: u64 = 0xe964;
+
 static TABLE: Lazy<ZobristTable> = Lazy::new(|| {
     use rand::{Rng, SeedableRng};
 
-    let mut rng = rand_chacha::ChaCha8Rng::seed_from_u64(0xe964);
+    let mut rng = rand_chacha::ChaCha8Rng::seed_from_u64(ZOBRIST_SEED);
     let mut board = [[[0; PieceKind::NUM]; Color::NUM]; Square::NUM];
     let mut hands = [[[0; 18]; Hand::NUM_HAND_PIECES]; Color::NUM];
     for v in &mut board {
@@ -164,11 +441,25 @@ static TABLE: Lazy<ZobristTable> = Lazy::new(|| {
             }
         }
     }
-    ZobristTable { board, hands }
+    let color = rng.random();
+    let mut locks = [[[0; PieceKind::NUM]; Color::NUM]; Square::NUM];
+    for v in &mut locks {
+        for v in v {
+            for v in v {
+                *v = rng.random();
+            }
+        }
+    }
+    let color_lock = rng.random();
+    ZobristTable {
+        board,
+        hands,
+        color,
+        locks,
+        color_lock,
+    }
 });
 
-const COLOR_HASH: Key = 1;
-
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -261,6 +552,14 @@ mod tests {
                 position.zobrist_hash(),
                 PositionWrapper::compute_hash(&position.inner)
             );
+            assert_eq!(
+                position.board_hash(),
+                PositionWrapper::compute_board_hash(&position.inner)
+            );
+            assert_eq!(
+                position.board_lock,
+                PositionWrapper::compute_board_lock(&position.inner)
+            );
         }
         assert_eq!(hashes[2], hashes[8]);
     }
@@ -268,15 +567,142 @@ mod tests {
     #[test]
     fn give_piece_works() {
         let piece_kind = PieceKind::Pawn;
-        let mut position = PartialPosition::startpos();
-        position
-            .hand_of_a_player_mut(Color::Black)
-            .Hand_add(piece_kind);
-        let mut position = PositionWrapper::new(position);
-        position.give_piece(piece_kind);
+        for side in Color::all() {
+            let mut position = PartialPosition::startpos();
+            position.side_to_move_set(side);
+            position.hand_of_a_player_mut(side).Hand_add(piece_kind);
+            let mut position = PositionWrapper::new(position);
+            position.give_piece(piece_kind);
+            assert_eq!(
+                position.inner.hand_of_a_player(side).count(piece_kind),
+                Some(0)
+            );
+            assert_eq!(
+                position
+                    .inner
+                    .hand_of_a_player(side.flip())
+                    .count(piece_kind),
+                Some(1)
+            );
+            assert_eq!(
+                position.hash,
+                PositionWrapper::compute_hash(&position.inner),
+            );
+        }
+    }
+
+    #[test]
+    fn checks_leave_no_self_check() {
+        use shogi_core::ToUsi;
+        use shogi_usi_parser::FromUsi;
+
+        // 攻め方の玉に飛車で王手がかかっているので、1 二金は詰みでも指せない。
+        let position = PositionWrapper::new(
+            PartialPosition::from_usi("sfen r7k/9/8P/9/9/9/9/9/K8 b G 1").unwrap(),
+        );
+        assert_eq!(position.all_checks(), []);
+        assert_eq!(position.mate_in_one(), None);
+        // 合駒で王手を外しつつ王手をかける手は指せる。
+        let position = PositionWrapper::new(
+            PartialPosition::from_usi("sfen 4k4/9/9/9/9/9/9/9/K7r b L 1").unwrap(),
+        );
+        let checks: Vec<_> = position
+            .all_checks()
+            .into_iter()
+            .map(|mv| mv.to_usi_owned())
+            .collect();
+        assert_eq!(checks, ["L*5i"]);
+    }
+
+    #[test]
+    fn mate_in_one_works() {
+        use shogi_usi_parser::FromUsi;
+
+        // 1 二金で詰み
+        let position = PositionWrapper::new(
+            PartialPosition::from_usi("sfen 8k/9/8P/9/9/9/9/9/9 b G 1").unwrap(),
+        );
+        let mate = Move::Drop {
+            piece: Piece::B_G,
+            to: Square::SQ_1B,
+        };
+        assert_eq!(position.mate_in_one(), Some(mate));
+        let checks = position.all_checks();
+        let mates: Vec<_> = position.mates_in_one_among(&checks).collect();
+        assert_eq!(mates, [mate]);
+
+        // 歩がなければ玉で金を取れる
+        let position = PositionWrapper::new(
+            PartialPosition::from_usi("sfen 8k/9/9/9/9/9/9/9/9 b G 1").unwrap(),
+        );
+        assert_eq!(position.mate_in_one(), None);
+
+        // 打ち歩詰めは詰みとしない
+        let position = PositionWrapper::new(
+            PartialPosition::from_usi("sfen 7nk/6G2/9/8L/9/9/9/9/9 b P 1").unwrap(),
+        );
+        assert_eq!(position.mate_in_one(), None);
+    }
+
+    #[test]
+    fn selfmate_in_one_works() {
+        use shogi_usi_parser::FromUsi;
+
+        // 1 二金に同馬と取るしかなく、1 一の玉が詰む。
+        let position = PartialPosition::from_usi("sfen 8K/9/7+bk/9/7+B1/9/9/9/9 b G 1").unwrap();
+        let position = PositionWrapper::new(position);
+        let checks = position.all_checks();
+        assert_eq!(
+            position.selfmate_in_one_among(&checks),
+            Some(Move::from_usi("G*1b").unwrap())
+        );
+        // 1 手詰めは自玉詰ではない。
+        let position = PartialPosition::from_usi("sfen 4k4/9/4P4/9/9/9/9/9/4K4 b G 1").unwrap();
+        let position = PositionWrapper::new(position);
         assert_eq!(
-            position.hash,
-            PositionWrapper::compute_hash(&position.inner),
+            position.selfmate_in_one_among(&[Move::from_usi("G*5b").unwrap()]),
+            None
         );
     }
+
+    #[test]
+    fn unmake_move_restores_position() {
+        use shogi_usi_parser::FromUsi;
+
+        // 成り・駒取り・成駒取り・駒打ちを含む局面
+        let sfens = [
+            "lnsgkgsnl/1r5b1/ppppppppp/9/9/2P6/PP1PPPPPP/1B5R1/LNSGKGSNL w - 2",
+            "4k4/4+r4/9/4B4/9/9/9/4L4/4K4 b GSp 1",
+            "8k/9/9/9/9/9/9/9/9 b G2r2b3g4s4n4l18p 1",
+        ];
+        for sfen in sfens {
+            let mut position =
+                PositionWrapper::new(PartialPosition::from_usi(&format!("sfen {}", sfen)).unwrap());
+            let original = position.clone();
+            let moves = shogi_legality_lite::all_legal_moves_partial(position.inner());
+            assert!(!moves.is_empty());
+            for mv in moves {
+                let hash_after = position.hash_after(mv);
+                let key_after = position.df_pn_key_after(mv);
+                let undo = position.make_move(mv);
+                assert_eq!(hash_after, position.zobrist_hash(), "{} {:?}", sfen, mv);
+                assert_eq!(key_after, position.df_pn_key(), "{} {:?}", sfen, mv);
+                position.unmake_move(undo);
+                assert_eq!(position.zobrist_hash(), original.zobrist_hash());
+                assert_eq!(position.board_hash(), original.board_hash());
+                assert_eq!(position.board_lock, original.board_lock);
+                assert_eq!(position.inner().to_sfen_owned(), sfen);
+                assert_eq!(
+                    position.inner().king_position(Color::Black),
+                    original.inner().king_position(Color::Black)
+                );
+                for color in Color::all() {
+                    assert_eq!(
+                        position.inner().player_bitboard(color),
+                        original.inner().player_bitboard(color)
+                    );
+                }
+            }
+        }
+    }
 }
@@ -0,0 +1,108 @@
+//! 自玉詰 (自殺詰) の解図。
+//!
+//! 攻め方は王手をかけ続け、玉方に攻め方の玉を詰まさせる。玉方は攻め方の玉を詰まさないように王手を外し、
+//! 詰ますしかなくなれば負けになる。玉方の玉を詰ませてしまうのは攻め方の失敗である。
+use shogi_core::{Move, PartialPosition};
+
+use crate::{
+    df_pn::search::{selfmate_with_limits, Outcome, SearchLimits, SearchStats},
+    eval::selfmate,
+    position_wrapper::PositionWrapper,
+    tt::DfPnTable,
+    validate, ErrType, Resolution,
+};
+
+/// 自玉詰になるかを調べる。自玉詰なら最短の手順も返す。
+///
+/// 手番側を攻め方とする。置換表は hash_mb メガバイト確保する。
+/// limits は df-pn と最短手順の探索のそれぞれに適用する。最短手順の探索が打ち切られたら
+/// [`Resolution::Unknown`] のエラーを返す。
+pub fn solve(
+    position: &PartialPosition,
+    hash_mb: usize,
+    limits: &SearchLimits,
+) -> Result<(Outcome, Vec<Move>), ErrType> {
+    validate::validate(position)?;
+    if position.king_position(position.side_to_move()).is_none() {
+        return Err(ErrType {
+            resolution: Resolution::Invalid,
+            reason: "attacker has no king".to_string(),
+        });
+    }
+    let df_pn = DfPnTable::with_megabytes(hash_mb);
+    let position = PositionWrapper::new(position.clone());
+    let outcome = selfmate_with_limits(
+        &df_pn,
+        &position,
+        false,
+        &mut SearchStats::default(),
+        &Default::default(),
+        &Default::default(),
+        limits,
+    );
+    if outcome != Outcome::Mate {
+        return Ok((outcome, vec![]));
+    }
+    match selfmate::search_with_limits(&position, &df_pn, &mut Default::default(), limits) {
+        Some(moves) => Ok((outcome, moves)),
+        None => Err(ErrType {
+            resolution: Resolution::Unknown,
+            reason: "shortest selfmate line was not found within the limits".to_string(),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shogi_core::ToUsi;
+    use shogi_usi_parser::FromUsi;
+
+    fn solve_sfen(sfen: &str) -> (Outcome, Vec<String>) {
+        let position = PartialPosition::from_usi(&format!("sfen {}", sfen)).unwrap();
+        let limits = SearchLimits {
+            max_nodes: Some(100_000),
+            ..Default::default()
+        };
+        let (outcome, moves) = solve(&position, 1, &limits).unwrap();
+        (outcome, moves.iter().map(|mv| mv.to_usi_owned()).collect())
+    }
+
+    #[test]
+    fn shortest_selfmates_are_found() {
+        let (outcome, moves) = solve_sfen("8K/9/7+bk/9/7+B1/9/9/9/9 b G 1");
+        assert_eq!(outcome, Outcome::Mate);
+        assert_eq!(moves, ["G*1b", "2c1b"]);
+        let (outcome, moves) = solve_sfen("5k2K/4R4/7+b1/4+R4/9/9/9/9/9 b - 1");
+        assert_eq!(outcome, Outcome::Mate);
+        assert_eq!(moves, ["5d4c", "4a3a", "5b2b+", "2c2b"]);
+    }
+
+    #[test]
+    fn mating_the_defender_is_not_a_selfmate() {
+        let sfen = "8k/6n2/7L1/6N2/9/9/9/9/5K3 b - 1";
+        let position = PartialPosition::from_usi(&format!("sfen {}", sfen)).unwrap();
+        assert!(PositionWrapper::new(position).mate_in_one().is_some());
+        assert_eq!(solve_sfen(sfen), (Outcome::NoMate, vec![]));
+    }
+
+    #[test]
+    fn solve_stops_at_limits() {
+        let position =
+            PartialPosition::from_usi("sfen 5k2K/4R4/7+b1/4+R4/9/9/9/9/9 b - 1").unwrap();
+        let limits = SearchLimits {
+            max_nodes: Some(1),
+            ..Default::default()
+        };
+        let (outcome, moves) = solve(&position, 1, &limits).unwrap();
+        assert!(matches!(outcome, Outcome::Unknown(_)));
+        assert!(moves.is_empty());
+    }
+
+    #[test]
+    fn invalid_positions_are_rejected() {
+        let position = PartialPosition::from_usi("sfen 4k4/9/9/9/9/9/9/9/9 b G 1").unwrap();
+        let error = solve(&position, 1, &SearchLimits::default()).unwrap_err();
+        assert_eq!(error.reason, "attacker has no king");
+    }
+}
@@ -0,0 +1,264 @@
+//! 置換表をファイルに保存して、後から探索を再開するための形式。
+//!
+//! ファイルは表ごとに、ヘッダー (識別子、形式の版、Zobrist ハッシュの種とビット数、表の種類、エントリーの個数) と
+//! エントリーの列 (キー、手間、値) を並べたもの。df-pn の表のキーには盤面の検査用の値も含む。数値はすべてリトルエンディアン。
+//! ヘッダーが一致しないファイルは、別の版やハッシュで作られたものとして読み込まない。
+use std::io::{self, Read, Write};
+
+use shogi_core::{Hand, Move, Piece, Square};
+
+use crate::{
+    eval::Value,
+    hand,
+    position_wrapper::{Key, ZOBRIST_SEED},
+    tt::{DfPnEntry, PathDependency},
+};
+
+const MAGIC: [u8; 8] = *b"MATESNAP";
+
+/// エントリーの形式の版。エントリーの中身を変えたら上げる。
+pub const LAYOUT_VERSION: u32 = 3;
+
+// キーのバイト数
+const KEY_BYTES: u8 = std::mem::size_of::<Key>() as u8;
+
+/// ファイルに保存できる置換表のキー。
+pub trait SnapshotKey: Sized {
+    fn write_to(&self, w: &mut impl Write) -> io::Result<()>;
+    fn read_from(r: &mut impl Read) -> io::Result<Self>;
+}
+
+impl SnapshotKey for Key {
+    fn write_to(&self, w: &mut impl Write) -> io::Result<()> {
+        write_key(w, *self)
+    }
+
+    fn read_from(r: &mut impl Read) -> io::Result<Self> {
+        read_key(r)
+    }
+}
+
+/// ファイルに保存できる置換表の値。
+pub trait SnapshotValue: Sized {
+    /// 表の種類。別の種類の表として読み込まないために使う。
+    const KIND: u8;
+
+    fn write_to(&self, w: &mut impl Write) -> io::Result<()>;
+    fn read_from(r: &mut impl Read) -> io::Result<Self>;
+}
+
+/// ヘッダーを書き込む。
+pub(crate) fn write_header<V: SnapshotValue>(w: &mut impl Write, len: u64) -> io::Result<()> {
+    w.write_all(&MAGIC)?;
+    write_u32(w, LAYOUT_VERSION)?;
+    write_u64(w, ZOBRIST_SEED)?;
+    write_u8(w, KEY_BYTES)?;
+    write_u8(w, V::KIND)?;
+    write_u64(w, len)
+}
+
+/// ヘッダーを読んで検証し、エントリーの個数を返す。
+pub(crate) fn read_header<V: SnapshotValue>(r: &mut impl Read) -> io::Result<u64> {
+    let mut magic = [0; 8];
+    r.read_exact(&mut magic)?;
+    if magic != MAGIC {
+        return Err(invalid("not a transposition table snapshot"));
+    }
+    let version = read_u32(r)?;
+    if version != LAYOUT_VERSION {
+        return Err(invalid(format!(
+            "snapshot layout version {} is not supported (expected {})",
+            version, LAYOUT_VERSION
+        )));
+    }
+    let seed = read_u64(r)?;
+    if seed != ZOBRIST_SEED {
+        return Err(invalid(format!(
+            "snapshot was made with Zobrist seed {:#x} (expected {:#x})",
+            seed, ZOBRIST_SEED
+        )));
+    }
+    let key_bytes = read_u8(r)?;
+    if key_bytes != KEY_BYTES {
+        return Err(invalid(format!(
+            "snapshot was made with {}-bit keys (expected {})",
+            key_bytes as u32 * 8,
+            KEY_BYTES as u32 * 8
+        )));
+    }
+    let kind = read_u8(r)?;
+    if kind != V::KIND {
+        return Err(invalid(format!(
+            "snapshot holds table kind {} (expected {})",
+            kind,
+            V::KIND
+        )));
+    }
+    read_u64(r)
+}
+
+impl SnapshotValue for DfPnEntry {
+    const KIND: u8 = 1;
+
+    fn write_to(&self, w: &mut impl Write) -> io::Result<()> {
+        write_hand(w, self.mine)?;
+        write_hand(w, self.yours)?;
+        write_u32(w, self.phi)?;
+        write_u32(w, self.delta)?;
+        match self.path {
+            Some(path) => {
+                write_u8(w, 1)?;
+                write_key(w, path.path_key)?;
+                write_u32(w, path.depth)?;
+            }
+            None => write_u8(w, 0)?,
+        }
+        match self.source {
+            Some(source) => {
+                write_u8(w, 1)?;
+                write_key(w, source)
+            }
+            None => write_u8(w, 0),
+        }
+    }
+
+    fn read_from(r: &mut impl Read) -> io::Result<Self> {
+        let mine = read_hand(r)?;
+        let yours = read_hand(r)?;
+        let phi = read_u32(r)?;
+        let delta = read_u32(r)?;
+        let path = match read_u8(r)? {
+            0 => None,
+            1 => Some(PathDependency {
+                path_key: read_key(r)?,
+                depth: read_u32(r)?,
+            }),
+            _ => return Err(invalid("broken path dependency")),
+        };
+        let source = match read_u8(r)? {
+            0 => None,
+            1 => Some(read_key(r)?),
+            _ => return Err(invalid("broken source")),
+        };
+        Ok(DfPnEntry {
+            mine,
+            yours,
+            phi,
+            delta,
+            path,
+            source,
+        })
+    }
+}
+
+impl SnapshotValue for (Value, Option<Move>) {
+    const KIND: u8 = 2;
+
+    fn write_to(&self, w: &mut impl Write) -> io::Result<()> {
+        write_u32(w, self.0 .0)?;
+        match self.1 {
+            None => write_u8(w, 0),
+            Some(Move::Normal { from, to, promote }) => {
+                write_u8(w, 1)?;
+                write_u8(w, from.index())?;
+                write_u8(w, to.index())?;
+                write_u8(w, promote as u8)
+            }
+            Some(Move::Drop { piece, to }) => {
+                write_u8(w, 2)?;
+                write_u8(w, piece.as_u8())?;
+                write_u8(w, to.index())
+            }
+        }
+    }
+
+    fn read_from(r: &mut impl Read) -> io::Result<Self> {
+        let value = Value(read_u32(r)?);
+        let mv = match read_u8(r)? {
+            0 => None,
+            1 => Some(Move::Normal {
+                from: read_square(r)?,
+                to: read_square(r)?,
+                promote: read_u8(r)? != 0,
+            }),
+            2 => {
+                let piece = read_u8(r)?;
+                let piece = Piece::all()
+                    .into_iter()
+                    .find(|p| p.as_u8() == piece)
+                    .ok_or_else(|| invalid("broken piece"))?;
+                Some(Move::Drop {
+                    piece,
+                    to: read_square(r)?,
+                })
+            }
+            _ => return Err(invalid("broken move")),
+        };
+        Ok((value, mv))
+    }
+}
+
+pub(crate) fn write_u8(w: &mut impl Write, value: u8) -> io::Result<()> {
+    w.write_all(&[value])
+}
+
+pub(crate) fn write_u32(w: &mut impl Write, value: u32) -> io::Result<()> {
+    w.write_all(&value.to_le_bytes())
+}
+
+pub(crate) fn write_u64(w: &mut impl Write, value: u64) -> io::Result<()> {
+    w.write_all(&value.to_le_bytes())
+}
+
+pub(crate) fn write_key(w: &mut impl Write, value: Key) -> io::Result<()> {
+    w.write_all(&value.to_le_bytes())
+}
+
+pub(crate) fn read_u8(r: &mut impl Read) -> io::Result<u8> {
+    let mut buf = [0; 1];
+    r.read_exact(&mut buf)?;
+    Ok(buf[0])
+}
+
+pub(crate) fn read_u32(r: &mut impl Read) -> io::Result<u32> {
+    let mut buf = [0; 4];
+    r.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+pub(crate) fn read_u64(r: &mut impl Read) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    r.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+pub(crate) fn read_key(r: &mut impl Read) -> io::Result<Key> {
+    let mut buf = [0; KEY_BYTES as usize];
+    r.read_exact(&mut buf)?;
+    Ok(Key::from_le_bytes(buf))
+}
+
+fn write_hand(w: &mut impl Write, value: Hand) -> io::Result<()> {
+    w.write_all(&hand::counts(value))
+}
+
+fn read_hand(r: &mut impl Read) -> io::Result<Hand> {
+    let mut counts = [0; Hand::NUM_HAND_PIECES];
+    r.read_exact(&mut counts)?;
+    if counts
+        .iter()
+        .zip(hand::MAX_COUNTS)
+        .any(|(&count, max)| count > max)
+    {
+        return Err(invalid("broken hand"));
+    }
+    Ok(hand::from_counts(counts))
+}
+
+fn read_square(r: &mut impl Read) -> io::Result<Square> {
+    Square::from_u8(read_u8(r)?).ok_or_else(|| invalid("broken square"))
+}
+
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
@@ -0,0 +1,223 @@
+//! 詰めろと必至の解析。
+//!
+//! 詰将棋ではなく実戦の局面について、手番側に詰めろがかかっているか、手番側が必至をかけられるかを調べる。
+//! どちらも df-pn で王手の連続で詰むかを調べて判定する。
+use shogi_core::{Move, PartialPosition};
+use shogi_legality_lite::is_in_check_partial_lite;
+
+use crate::{
+    df_pn::search::{df_pn_with_limits, Outcome, SearchLimits, SearchStats},
+    eval::search as evalsearch,
+    position_wrapper::PositionWrapper,
+    tt::{DfPnTable, EvalTable},
+    validate, ErrType,
+};
+
+/// 解析の結果。
+#[derive(Clone, Debug)]
+pub struct Analysis {
+    /// 手番側が手を渡したら相手が詰ますか (詰めろ)。手を渡せないときは [`Outcome::NoMate`]。
+    pub threat: Outcome,
+    /// 詰めろのときの相手の詰み手順。打ち切り条件に達して求められなかったときは空。
+    pub threat_line: Vec<Move>,
+    /// 必至をかける手。
+    pub hissi: Vec<Move>,
+    /// 打ち切り条件に達して、必至かどうか分からなかった手。
+    pub hissi_unknown: Vec<Move>,
+}
+
+/// 手番側への詰めろと、手番側がかけられる必至を調べる。
+///
+/// 置換表は詰めろ用と必至用にそれぞれ hash_mb メガバイト確保する。
+/// limits は df-pn や詰み手順の探索を呼ぶたびに適用するので、max_nodes は 1 回あたりの上限になる。
+pub fn analyze(
+    position: &PartialPosition,
+    hash_mb: usize,
+    limits: &SearchLimits,
+) -> Result<Analysis, ErrType> {
+    validate::validate(position)?;
+    let mut df_pn = DfPnTable::with_megabytes(hash_mb);
+    let mut evals = EvalTable::with_megabytes(hash_mb);
+    let (threat, threat_line) = mate_threat(position, &mut df_pn, &mut evals, limits);
+    // 攻め方が入れ替わるので、表は使い回さない。
+    let df_pn = DfPnTable::with_megabytes(hash_mb);
+    let (hissi, hissi_unknown) = hissi_moves(position, &df_pn, limits);
+    Ok(Analysis {
+        threat,
+        threat_line,
+        hissi,
+        hissi_unknown,
+    })
+}
+
+/// 手番側に詰めろがかかっているか調べる。詰めろなら相手の詰み手順も返す。
+///
+/// 手番側に王手がかかっているときや玉がないときは、手を渡せないので [`Outcome::NoMate`] を返す。
+/// df_pn と evals は相手を攻め方とする表として使う。
+pub fn mate_threat(
+    position: &PartialPosition,
+    df_pn: &mut DfPnTable,
+    evals: &mut EvalTable,
+    limits: &SearchLimits,
+) -> (Outcome, Vec<Move>) {
+    let side = position.side_to_move();
+    if position.king_position(side).is_none() || is_in_check_partial_lite(position) {
+        return (Outcome::NoMate, vec![]);
+    }
+    let mut passed = position.clone();
+    passed.side_to_move_set(side.flip());
+    let outcome = prove(df_pn, &PositionWrapper::new(passed.clone()), limits);
+    if outcome != Outcome::Mate {
+        return (outcome, vec![]);
+    }
+    let value = evalsearch::search_with_limits(
+        &passed,
+        df_pn,
+        evals,
+        false,
+        &mut Default::default(),
+        &mut Default::default(),
+        &Default::default(),
+        limits,
+    );
+    // 詰みは証明済みなので、手順が求められなくても詰めろである。
+    let Some(value) = value else {
+        return (outcome, vec![]);
+    };
+    if !value.is_mate() {
+        return (Outcome::NoMate, vec![]);
+    }
+    let line = evalsearch::mate_sequence(
+        &passed,
+        df_pn,
+        evals,
+        value,
+        false,
+        &Default::default(),
+        limits,
+    )
+    .unwrap_or_default();
+    (outcome, line)
+}
+
+/// 手番側が必至をかける手を探す。必至をかける手と、分からなかった手を返す。
+///
+/// 王手でない手のうち、相手がどう応じても王手の連続で詰む手を必至とする。
+/// 相手の応手が王手なら、王手を外しながら王手をかけて詰ませられるときだけ詰みとみなす。
+/// df_pn は手番側を攻め方とする表として使う。
+pub fn hissi_moves(
+    position: &PartialPosition,
+    df_pn: &DfPnTable,
+    limits: &SearchLimits,
+) -> (Vec<Move>, Vec<Move>) {
+    let mut position = PositionWrapper::new(position.clone());
+    let mut hissi = vec![];
+    let mut unknown = vec![];
+    for mv in position.all_evasions() {
+        let undo = position.make_move(mv);
+        if is_in_check_partial_lite(position.inner()) {
+            position.unmake_move(undo);
+            continue;
+        }
+        let mut result = Outcome::Mate;
+        for reply in position.all_evasions() {
+            let reply_undo = position.make_move(reply);
+            let outcome = prove(df_pn, &position, limits);
+            position.unmake_move(reply_undo);
+            match outcome {
+                Outcome::Mate => {}
+                Outcome::NoMate => {
+                    result = Outcome::NoMate;
+                    break;
+                }
+                Outcome::Unknown(value) => result = Outcome::Unknown(value),
+            }
+        }
+        position.unmake_move(undo);
+        match result {
+            Outcome::Mate => hissi.push(mv),
+            Outcome::NoMate => {}
+            Outcome::Unknown(_) => unknown.push(mv),
+        }
+    }
+    (hissi, unknown)
+}
+
+fn prove(df_pn: &DfPnTable, position: &PositionWrapper, limits: &SearchLimits) -> Outcome {
+    df_pn_with_limits(
+        df_pn,
+        position,
+        false,
+        &mut SearchStats::default(),
+        &Default::default(),
+        &Default::default(),
+        limits,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shogi_core::ToUsi;
+    use shogi_usi_parser::FromUsi;
+
+    fn usi(moves: &[Move]) -> Vec<String> {
+        moves.iter().map(|mv| mv.to_usi_owned()).collect()
+    }
+
+    fn analyze_sfen(sfen: &str) -> Analysis {
+        let position = PartialPosition::from_usi(&format!("sfen {}", sfen)).unwrap();
+        let limits = SearchLimits {
+            max_nodes: Some(100_000),
+            ..Default::default()
+        };
+        analyze(&position, 1, &limits).unwrap()
+    }
+
+    #[test]
+    fn mate_threat_works() {
+        // 5 九の玉は頭金で詰む。
+        let analysis = analyze_sfen("4k4/9/9/9/9/9/4p4/9/4K4 b g 1");
+        assert_eq!(analysis.threat, Outcome::Mate);
+        assert_eq!(usi(&analysis.threat_line), ["G*5h"]);
+        let analysis = analyze_sfen("4k4/9/4P4/9/9/9/9/9/4K4 w G 1");
+        assert_eq!(analysis.threat, Outcome::Mate);
+        assert_eq!(usi(&analysis.threat_line), ["G*5b"]);
+        let analysis = analyze_sfen("4k4/9/9/9/9/9/9/9/4K4 b g 1");
+        assert_eq!(analysis.threat, Outcome::NoMate);
+        assert!(analysis.threat_line.is_empty());
+        // 王手がかかっていると手を渡せない。
+        let analysis = analyze_sfen("4k4/9/9/9/9/9/9/4r4/4K4 b g 1");
+        assert_eq!(analysis.threat, Outcome::NoMate);
+    }
+
+    #[test]
+    fn hissi_moves_are_found() {
+        let analysis = analyze_sfen("8k/9/9/9/9/9/9/9/K8 b 2G 1");
+        assert_eq!(usi(&analysis.hissi), ["G*1c", "G*2c", "G*3c"]);
+        assert!(analysis.hissi_unknown.is_empty());
+        // 飛車の王手に王手で応じられないので、必至にならない。
+        let analysis = analyze_sfen("8k/9/9/9/9/9/9/9/K8 b 2Gr 1");
+        assert!(analysis.hissi.is_empty());
+        assert!(analysis.hissi_unknown.is_empty());
+    }
+
+    #[test]
+    fn hissi_moves_report_unknown() {
+        let position = PartialPosition::from_usi("sfen 8k/9/9/9/9/9/9/9/K8 b 2G 1").unwrap();
+        let limits = SearchLimits {
+            max_nodes: Some(1),
+            ..Default::default()
+        };
+        let (hissi, unknown) = hissi_moves(&position, &DfPnTable::new(1 << 10), &limits);
+        assert!(hissi.is_empty());
+        assert!(unknown.contains(&Move::from_usi("G*2c").unwrap()));
+    }
+
+    #[test]
+    fn invalid_positions_are_rejected() {
+        let position = PartialPosition::from_usi("sfen 9/9/9/9/9/9/9/9/4K4 b G 1").unwrap();
+        let error = analyze(&position, 1, &SearchLimits::default()).unwrap_err();
+        assert_eq!(error.reason, "defender has no king");
+    }
+}
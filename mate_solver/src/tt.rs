@@ -1,78 +1,1039 @@
-use std::mem::MaybeUninit;
+use std::{
+    alloc::{self, Layout},
+    cell::Cell,
+    io::{self, Read, Write},
+    mem::MaybeUninit,
+    ops::{Deref, DerefMut},
+    ptr::NonNull,
+    sync::{Mutex, MutexGuard},
+};
 
-/// 置換表。1 バケットにつき 4 個のエントリーがある。
-pub struct Tt<V> {
-    pub sizes: Vec<u8>,
-    pub table: Vec<MaybeUninit<(u64, V)>>,
+use shogi_core::Hand;
+
+use crate::{
+    hand,
+    position_wrapper::Key,
+    snapshot::{self, SnapshotKey, SnapshotValue},
+};
+
+/// 置換表の大きさの既定値 (メガバイト)
+pub const DEFAULT_HASH_MB: usize = 16;
+
+/// 置換表のキー。
+///
+/// エントリーにはキー全体を入れて比べるので、バケットの位置を決める下位ビットが同じでも、
+/// キーが異なれば別の局面として扱われる。
+pub trait TtKey: Copy + Eq {
+    /// バケットの位置を決める下位 64 ビット
+    fn low_bits(self) -> u64;
+}
+
+impl TtKey for u64 {
+    #[inline(always)]
+    fn low_bits(self) -> u64 {
+        self
+    }
+}
+
+impl TtKey for u128 {
+    #[inline(always)]
+    fn low_bits(self) -> u64 {
+        self as u64
+    }
+}
+
+/// 置換表に入れる値。
+///
+/// 表を引くたびに読む部分 (Hot) はバケットのキャッシュラインに入れ、
+/// 残り (Cold) はキー全体と一緒にラインの外に置く。
+pub trait TtValue: Copy {
+    type Hot: Copy;
+    type Cold: Copy;
+
+    fn split(self) -> (Self::Hot, Self::Cold);
+    fn join(hot: Self::Hot, cold: Self::Cold) -> Self;
+}
+
+// 丸ごとバケットのラインに入る値。
+macro_rules! impl_tt_value_in_line {
+    ($($ty:ty),*) => {
+        $(
+            impl TtValue for $ty {
+                type Hot = Self;
+                type Cold = ();
+
+                #[inline(always)]
+                fn split(self) -> (Self, ()) {
+                    (self, ())
+                }
+
+                #[inline(always)]
+                fn join(hot: Self, _: ()) -> Self {
+                    hot
+                }
+            }
+        )*
+    };
+}
+
+impl_tt_value_in_line!(
+    i32,
+    u32,
+    u64,
+    (crate::eval::value::Value, Option<shogi_core::Move>)
+);
+
+/// あふれたエントリーを入れるバケットの組に入るエントリーの個数
+const SPILL_ENTRIES: usize = 8;
+
+/// 置換表。1 バケットにつき N 個のエントリーがある。
+///
+/// 各エントリーはそれを得るのに費やした探索の手間 (ノード数) を持ち、バケットが一杯なら手間の最も小さいものを追い出す。
+pub struct Tt<V: TtValue, K = Key, const N: usize = 4> {
+    buckets: Lines<Bucket<V, N>>,
+    /// バケットと同じ位置に、そのエントリーのキー全体と値の残りを置く。
+    colds: Lines<ColdSlots<K, V, N>>,
+    // 入っているエントリーの個数
+    len: usize,
+    // 引いた回数と見つかった回数。引くのは &self なので Cell に入れる。
+    probes: Cell<u64>,
+    hits: Cell<u64>,
+    replacements: u64,
+    bucket_full: u64,
+}
+
+/// 置換表の利用状況。
+///
+/// 探索が遅くなったとき、表が足りないのか探索そのものが変わったのかを見分けるのに使う。
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct TtStats {
+    /// 表を引いた回数
+    pub probes: u64,
+    /// 使えるエントリーが見つかった回数
+    pub hits: u64,
+    /// 使えるエントリーが見つからなかった回数
+    pub misses: u64,
+    /// 挿入のときに同じキーの古いエントリーを取り除いた回数
+    pub replacements: u64,
+    /// バケットが一杯で、他のエントリーを追い出して挿入した回数
+    pub bucket_full: u64,
 }
 
-impl<V: Copy> Tt<V> {
+impl std::ops::AddAssign for TtStats {
+    fn add_assign(&mut self, other: Self) {
+        self.probes += other.probes;
+        self.hits += other.hits;
+        self.misses += other.misses;
+        self.replacements += other.replacements;
+        self.bucket_full += other.bucket_full;
+    }
+}
+
+/// 置換表のバケット。1 キャッシュライン (64 バイト) に収まり、ラインの境界から始まる。
+///
+/// エントリーの個数、キーの断片、手間と、値のうち引くたびに読む部分 ([`TtValue::Hot`]) を詰める。
+/// キー全体と値の残りは別の配列に置き、キーの断片が一致したときだけ読む。
+/// df-pn の表では 1 ラインに 2 エントリー、最短手順探索の表では 4 エントリーが入る。
+///
+/// 一杯のときは同じ組のバケット (位置の下位ビットだけが違い、組全体で 8 エントリー入るもの) にあふれさせ、
+/// spilled を立てる。盤面だけで位置を決める表では、持ち駒違いのエントリーが同じバケットに集まるためである。
+#[repr(C, align(64))]
+struct Bucket<V: TtValue, const N: usize> {
+    len: u8,
+    /// 同じ組の他のバケットにあふれたエントリーがあるか
+    spilled: bool,
+    /// キーの断片。バケットの位置を決めるのとは別のビットを使う。
+    tags: [u16; N],
+    efforts: [u32; N],
+    hots: [MaybeUninit<V::Hot>; N],
+}
+
+/// バケットのエントリーのキー全体と値の残り。
+type ColdSlots<K, V, const N: usize> = [MaybeUninit<(K, <V as TtValue>::Cold)>; N];
+
+#[inline(always)]
+fn tag<K: TtKey>(key: K) -> u16 {
+    (key.low_bits() >> 48) as u16
+}
+
+/// バケットの配列の位置。ロックを取らずにバケットを先読みするのに使う。
+///
+/// 指す先は読み書きせず CPU に先読みを指示するだけなので、表の中身が変わっても構わない。
+/// 表より長く使ってはならない。
+struct Prefetcher<V: TtValue, const N: usize> {
+    buckets: NonNull<Bucket<V, N>>,
+    mask: u64,
+}
+
+impl<V: TtValue, const N: usize> Clone for Prefetcher<V, N> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<V: TtValue, const N: usize> Copy for Prefetcher<V, N> {}
+
+// 先読みの指示にしか使わない。
+unsafe impl<V: TtValue, const N: usize> Send for Prefetcher<V, N> {}
+unsafe impl<V: TtValue, const N: usize> Sync for Prefetcher<V, N> {}
+
+impl<V: TtValue, const N: usize> Prefetcher<V, N> {
+    /// key の入るバケットをキャッシュに読み込むよう CPU に指示する。
+    ///
+    /// キー全体と値の残りは、キーの断片が一致したときにしか読まないので先読みしない。
+    #[inline(always)]
+    fn prefetch(&self, key: impl TtKey) {
+        #[cfg(target_arch = "x86_64")]
+        unsafe {
+            use std::arch::x86_64::{_mm_prefetch, _MM_HINT_T0};
+            let index = (key.low_bits() & self.mask) as usize;
+            _mm_prefetch::<_MM_HINT_T0>(self.buckets.as_ptr().add(index) as *const i8);
+        }
+        #[cfg(not(target_arch = "x86_64"))]
+        let _ = key;
+    }
+}
+
+/// キャッシュラインの境界に揃えた配列。
+///
+/// すべて 0 の要素は空のバケットである。64 バイトに揃えて確保すると確保時に全体を 0 で埋めてしまうので、
+/// 少し大きめの領域を calloc で確保して先頭をずらす。大きな表でも、触れたページにしかメモリーは割り当てられない。
+struct Lines<T> {
+    ptr: NonNull<T>,
+    len: usize,
+    raw: NonNull<u8>,
+    layout: Layout,
+}
+
+// T の配列を所有するのと同じ。
+unsafe impl<T: Send> Send for Lines<T> {}
+unsafe impl<T: Sync> Sync for Lines<T> {}
+
+impl<T> Lines<T> {
+    fn new(len: usize) -> Self {
+        let line = std::mem::align_of::<T>().max(64);
+        let size = len * std::mem::size_of::<T>() + line;
+        let layout = Layout::from_size_align(size, 16).unwrap();
+        let raw = NonNull::new(unsafe { alloc::alloc_zeroed(layout) })
+            .unwrap_or_else(|| alloc::handle_alloc_error(layout));
+        let offset = raw.as_ptr().align_offset(line);
+        let ptr = unsafe { raw.add(offset) }.cast();
+        Self {
+            ptr,
+            len,
+            raw,
+            layout,
+        }
+    }
+}
+
+impl<T> Deref for Lines<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl<T> DerefMut for Lines<T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl<T> Drop for Lines<T> {
+    fn drop(&mut self) {
+        unsafe { alloc::dealloc(self.raw.as_ptr(), self.layout) }
+    }
+}
+
+impl<V: TtValue, K: TtKey, const N: usize> Tt<V, K, N> {
     /// size は 2 ベキでなければならない。
     pub fn new(size: usize) -> Self {
         assert_eq!(size % 2, 0);
         assert!(size.is_power_of_two());
         Self {
-            sizes: vec![0; size / 2], // size for index, 4 bits for each entry
-            table: vec![MaybeUninit::uninit(); 4 * size],
+            buckets: Lines::new(size),
+            colds: Lines::new(size),
+            len: 0,
+            probes: Cell::new(0),
+            hits: Cell::new(0),
+            replacements: 0,
+            bucket_full: 0,
         }
     }
+
+    /// およそ mb メガバイトに収まる置換表を作る。
+    pub fn with_megabytes(mb: usize) -> Self {
+        Self::new(Self::size_for_megabytes(mb))
+    }
+
+    // mb メガバイトに収まるバケットの個数 (2 ベキ)
+    fn size_for_megabytes(mb: usize) -> usize {
+        let buckets = (mb << 20) / Self::bucket_bytes();
+        if buckets < 2 {
+            2
+        } else {
+            1 << buckets.ilog2()
+        }
+    }
+
+    // 1 バケットあたりのバイト数。ラインの外に置く部分も含む。
+    fn bucket_bytes() -> usize {
+        std::mem::size_of::<Bucket<V, N>>() + std::mem::size_of::<ColdSlots<K, V, N>>()
+    }
+
     #[inline(always)]
     pub fn size(&self) -> usize {
-        self.table.len() / 4
-    }
-    pub fn fetch(&self, key: u64) -> Option<V> {
-        let len = self.size();
-        let index = key % len as u64;
-        let size = ((self.sizes[index as usize / 2] >> (4 * (index % 2))) & 0x7) as usize;
-        debug_assert!(size <= 4);
-        for i in 0..size {
-            let &(thiskey, value) = unsafe { self.table[4 * index as usize + i].assume_init_ref() };
-            if thiskey == key {
-                return Some(value);
-            }
+        self.buckets.len()
+    }
+
+    /// 入っているエントリーの個数
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// 入れられるエントリーの個数
+    pub fn capacity(&self) -> usize {
+        N * self.size()
+    }
+
+    /// 使用率 (千分率)
+    pub fn hashfull(&self) -> u32 {
+        (self.len * 1000 / self.capacity()) as u32
+    }
+
+    pub fn fetch(&self, key: K) -> Option<V> {
+        let result = self.entries(key).next();
+        self.record_probe(result.is_some());
+        result
+    }
+    pub fn insert(&mut self, key: K, value: V) {
+        self.insert_by(key, value, 0, |_| true);
+    }
+
+    /// 表を 1 回引いたことを記録する。entries で引いたときに使う。
+    #[inline(always)]
+    pub fn record_probe(&self, hit: bool) {
+        self.probes.set(self.probes.get() + 1);
+        if hit {
+            self.hits.set(self.hits.get() + 1);
+        }
+    }
+
+    /// 利用状況の統計。作ってから、または clear してからの累計。
+    pub fn stats(&self) -> TtStats {
+        TtStats {
+            probes: self.probes.get(),
+            hits: self.hits.get(),
+            misses: self.probes.get() - self.hits.get(),
+            replacements: self.replacements,
+            bucket_full: self.bucket_full,
         }
-        None
-    }
-    pub fn insert(&mut self, key: u64, value: V) {
-        let len = self.size();
-        let index = key % len as u64;
-        let size = ((self.sizes[index as usize / 2] >> (4 * (index % 2))) & 0x7) as usize;
-        debug_assert!(size <= 4);
-        for i in 0..size {
-            let &(thiskey, _) = unsafe { self.table[4 * index as usize + i].assume_init_ref() };
-            if thiskey == key {
-                // Replace this entry
-                self.table[4 * index as usize + i].write((key, value));
-                return;
+    }
+
+    /// key が一致するエントリーの値をすべて列挙する。表を引いた回数には数えない。
+    pub fn entries(&self, key: K) -> impl Iterator<Item = V> + '_ {
+        let (index, probed) = self.probed(key);
+        (0..probed)
+            .map(move |offset| index ^ offset)
+            .flat_map(move |index| self.matches(index, key).map(move |i| self.value(index, i)))
+    }
+
+    /// key の入るバケットをキャッシュに読み込むよう CPU に指示する。
+    #[inline(always)]
+    pub fn prefetch(&self, key: K) {
+        self.prefetcher().prefetch(key);
+    }
+
+    #[inline(always)]
+    fn prefetcher(&self) -> Prefetcher<V, N> {
+        Prefetcher {
+            buckets: self.buckets.ptr,
+            mask: self.size() as u64 - 1,
+        }
+    }
+
+    /// key が一致し、かつ replaces が true を返すエントリーをすべて取り除いてから value を挿入する。
+    ///
+    /// effort は value を得るのに費やした手間で、取り除いたエントリーの手間も引き継ぐ。
+    /// バケットが一杯なら同じ組の他のバケットに入れ、どれも一杯なら組の中で手間の最も小さいエントリーを追い出す。
+    pub fn insert_by(&mut self, key: K, value: V, mut effort: u32, replaces: impl Fn(&V) -> bool) {
+        let (index, probed) = self.probed(key);
+        let group = self.group();
+        for index in (0..probed).map(|offset| index ^ offset) {
+            let mut i = 0;
+            while i < self.buckets[index].len as usize {
+                if self.is_match(index, i, key) && replaces(&self.value(index, i)) {
+                    effort = effort.saturating_add(self.buckets[index].efforts[i]);
+                    self.remove(index, i);
+                    self.len -= 1;
+                    self.replacements += 1;
+                } else {
+                    i += 1;
+                }
             }
         }
-        let mut pos = 3;
-        if size <= 3 {
-            let newsize = (size + 1) as u8;
-            self.sizes[index as usize / 2] &= if index.is_multiple_of(2) { 0xf0 } else { 0x0f };
-            self.sizes[index as usize / 2] |= newsize << (4 * (index % 2));
-            pos = size;
+        let (target, pos) = if let Some(target) = (0..group)
+            .map(|offset| index ^ offset)
+            .find(|&target| (self.buckets[target].len as usize) < N)
+        {
+            let bucket = &mut self.buckets[target];
+            bucket.len += 1;
+            self.len += 1;
+            (target, bucket.len as usize - 1)
+        } else {
+            self.bucket_full += 1;
+            // 手間が同じなら他のバケットより元のバケットから、後から入ったものを追い出す。
+            (0..group)
+                .rev()
+                .map(|offset| index ^ offset)
+                .flat_map(|target| (0..N).map(move |i| (target, i)))
+                .rev()
+                .min_by_key(|&(target, i)| self.buckets[target].efforts[i])
+                .unwrap()
+        };
+        if target != index {
+            self.buckets[index].spilled = true;
         }
+        let (hot, cold) = value.split();
+        let bucket = &mut self.buckets[target];
+        bucket.tags[pos] = tag(key);
+        bucket.efforts[pos] = effort;
+        bucket.hots[pos].write(hot);
+        self.colds[target][pos].write((key, cold));
+    }
+
+    /// 手間の小さいエントリーから、全体のおよそ ratio の割合を取り除く。
+    pub fn collect_garbage(&mut self, ratio: f64) {
+        self.collect_garbage_by(ratio, |effort, _| effort);
+    }
+
+    /// priority (手間と値から決める) の小さいエントリーから、全体のおよそ ratio の割合を取り除く。
+    ///
+    /// priority が境目のエントリーは、合わせて ratio の割合を超えない分だけ取り除く。
+    pub fn collect_garbage_by<P: Ord + Copy>(
+        &mut self,
+        ratio: f64,
+        priority: impl Fn(u32, &V) -> P,
+    ) {
+        let count = (self.len as f64 * ratio) as usize;
+        if count == 0 {
+            return;
+        }
+        let mut priorities: Vec<P> = self
+            .slots()
+            .map(|(_, value, effort)| priority(effort, &value))
+            .collect();
+        let (below, &mut threshold, _) = priorities.select_nth_unstable(count - 1);
+        // 境目のエントリーのうち取り除く個数
+        let mut at_threshold = count - below.iter().filter(|&&p| p < threshold).count();
+        for index in 0..self.size() {
+            let mut i = 0;
+            while i < self.buckets[index].len as usize {
+                let p = priority(self.buckets[index].efforts[i], &self.value(index, i));
+                let removes = if p == threshold && at_threshold > 0 {
+                    at_threshold -= 1;
+                    true
+                } else {
+                    p < threshold
+                };
+                if removes {
+                    self.remove(index, i);
+                    self.len -= 1;
+                } else {
+                    i += 1;
+                }
+            }
+        }
+    }
+
+    // 大きさが 2 ベキなので、下位ビットを取り出すだけでよい。
+    #[inline(always)]
+    fn index(&self, key: K) -> usize {
+        (key.low_bits() & (self.size() as u64 - 1)) as usize
+    }
+
+    // key のエントリーがありうるバケットは、位置と 0 から probed 未満の値との排他的論理和の位置にある。
+    // (key の入るバケットの位置, probed) を返す。
+    #[inline(always)]
+    fn probed(&self, key: K) -> (usize, usize) {
+        let index = self.index(key);
+        let probed = if self.buckets[index].spilled {
+            self.group()
+        } else {
+            1
+        };
+        (index, probed)
+    }
+
+    // あふれたエントリーを入れるバケットの組の大きさ。組の中のバケットは位置の下位ビットだけが違う。
+    #[inline(always)]
+    fn group(&self) -> usize {
+        (SPILL_ENTRIES / N).clamp(1, self.size())
+    }
+
+    // index 番目のバケットで key が一致するエントリーの位置を列挙する。
+    #[inline(always)]
+    fn matches(&self, index: usize, key: K) -> impl Iterator<Item = usize> + '_ {
+        (0..self.buckets[index].len as usize).filter(move |&i| self.is_match(index, i, key))
+    }
+
+    // キーの断片を比べてから、一致したときだけラインの外のキー全体を比べる。
+    #[inline(always)]
+    fn is_match(&self, index: usize, i: usize, key: K) -> bool {
+        self.buckets[index].tags[i] == tag(key) && self.cold(index, i).0 == key
+    }
+
+    #[inline(always)]
+    fn cold(&self, index: usize, i: usize) -> &(K, V::Cold) {
+        debug_assert!(i < self.buckets[index].len as usize);
+        unsafe { self.colds[index][i].assume_init_ref() }
+    }
+
+    #[inline(always)]
+    fn value(&self, index: usize, i: usize) -> V {
+        debug_assert!(i < self.buckets[index].len as usize);
+        let hot = unsafe { self.buckets[index].hots[i].assume_init() };
+        V::join(hot, self.cold(index, i).1)
+    }
 
-        self.table[4 * index as usize + pos].write((key, value));
+    // index 番目のバケットの i 番目のエントリーを取り除き、末尾のエントリーで埋める。
+    #[inline(always)]
+    fn remove(&mut self, index: usize, i: usize) {
+        let bucket = &mut self.buckets[index];
+        let last = bucket.len as usize - 1;
+        bucket.tags.swap(i, last);
+        bucket.efforts.swap(i, last);
+        bucket.hots.swap(i, last);
+        bucket.len -= 1;
+        self.colds[index].swap(i, last);
     }
 
     pub fn clear(&mut self) {
-        for v in &mut self.sizes {
-            *v = 0;
+        for bucket in self.buckets.iter_mut() {
+            bucket.len = 0;
+            bucket.spilled = false;
         }
+        self.len = 0;
+        self.probes.set(0);
+        self.hits.set(0);
+        self.replacements = 0;
+        self.bucket_full = 0;
+    }
+
+    // 入っているエントリーをすべて (キー, 値, 手間) の形で列挙する。
+    fn slots(&self) -> impl Iterator<Item = (K, V, u32)> + '_ {
+        (0..self.size()).flat_map(move |index| {
+            (0..self.buckets[index].len as usize).map(move |i| {
+                (
+                    self.cold(index, i).0,
+                    self.value(index, i),
+                    self.buckets[index].efforts[i],
+                )
+            })
+        })
     }
 }
 
-/// df-pn 用の置換表。
-pub type DfPnTable = Tt<(u32, u32)>;
+impl<V: TtValue + SnapshotValue, K: TtKey + SnapshotKey, const N: usize> Tt<V, K, N> {
+    /// 中身をすべて書き出す。形式は [`snapshot`] を参照。
+    pub fn save(&self, w: &mut impl Write) -> io::Result<()> {
+        snapshot::write_header::<V>(w, self.len as u64)?;
+        for (key, value, effort) in self.slots() {
+            write_slot(w, key, &value, effort)?;
+        }
+        Ok(())
+    }
+
+    /// save で書き出したエントリーを読み込んで加える。表の大きさは保存したときと違ってもよい。
+    pub fn load(&mut self, r: &mut impl Read) -> io::Result<()> {
+        let len = snapshot::read_header::<V>(r)?;
+        for _ in 0..len {
+            let (key, value, effort) = read_slot(r)?;
+            self.insert_by(key, value, effort, |_| false);
+        }
+        Ok(())
+    }
+}
+
+fn write_slot<K: SnapshotKey, V: SnapshotValue>(
+    w: &mut impl Write,
+    key: K,
+    value: &V,
+    effort: u32,
+) -> io::Result<()> {
+    key.write_to(w)?;
+    snapshot::write_u32(w, effort)?;
+    value.write_to(w)
+}
+
+fn read_slot<K: SnapshotKey, V: SnapshotValue>(r: &mut impl Read) -> io::Result<(K, V, u32)> {
+    let key = K::read_from(r)?;
+    let effort = snapshot::read_u32(r)?;
+    let value = V::read_from(r)?;
+    Ok((key, value, effort))
+}
 
 /// 最短手順探索用の置換表。
 pub type EvalTable = Tt<(crate::eval::value::Value, Option<shogi_core::Move>)>;
 
+/// df-pn の置換表を引くための鍵。
+///
+/// 持ち駒だけが異なる局面は同じバケットに入り、持ち駒の優越関係を使って結果を共有する。
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct DfPnKey {
+    /// 盤面と手番のハッシュ値。持ち駒は含まない (自玉詰では含む。[`crate::df_pn::search::NodeKind::key`] を参照)。
+    pub board: Key,
+    /// 盤面と手番を board とは別の乱数で表した検査用の値。board が衝突した別の局面のエントリーを使わないために比べる。
+    pub lock: u32,
+    /// 手番側の持ち駒
+    pub mine: Hand,
+    /// 相手側の持ち駒
+    pub yours: Hand,
+}
+
+impl DfPnKey {
+    #[inline(always)]
+    fn board_key(&self) -> BoardKey {
+        BoardKey {
+            board: self.board,
+            lock: self.lock,
+        }
+    }
+}
+
+/// df-pn の置換表の中のキー。盤面のハッシュ値と検査用の値がどちらも一致するエントリーだけを同じ盤面のものとして扱う。
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct BoardKey {
+    board: Key,
+    lock: u32,
+}
+
+impl TtKey for BoardKey {
+    #[inline(always)]
+    fn low_bits(self) -> u64 {
+        self.board.low_bits()
+    }
+}
+
+impl SnapshotKey for BoardKey {
+    fn write_to(&self, w: &mut impl Write) -> io::Result<()> {
+        snapshot::write_key(w, self.board)?;
+        snapshot::write_u32(w, self.lock)
+    }
+
+    fn read_from(r: &mut impl Read) -> io::Result<Self> {
+        Ok(BoardKey {
+            board: snapshot::read_key(r)?,
+            lock: snapshot::read_u32(r)?,
+        })
+    }
+}
+
+/// df-pn の置換表のエントリー。
+///
+/// (phi, delta) は手番側から見た値で、phi = 0 なら手番側の勝ち、delta = 0 なら手番側の負けである。
+/// 勝ち負けが確定したエントリーの持ち駒は証明駒・反証駒であり、
+/// - 手番側の勝ちなら、手番側が mine 以上、相手側が yours 以下の駒を持つ局面すべてで勝ち
+/// - 手番側の負けなら、手番側が mine 以下、相手側が yours 以上の駒を持つ局面すべてで負け
+///
+/// となる。確定していないエントリーや、探索経路に依存するエントリーは持ち駒が完全に一致する局面にしか使わない。
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct DfPnEntry {
+    pub mine: Hand,
+    pub yours: Hand,
+    pub phi: u32,
+    pub delta: u32,
+    /// 千日手による結果を含み、探索経路に依存する場合はその経路
+    pub path: Option<PathDependency>,
+    /// phi の値を受け継いだ子ノードのハッシュ値 (SNDA)。
+    ///
+    /// 同じ局面から値を受け継いだ兄弟ノードの phi を二重に数えないために使う。
+    pub source: Option<Key>,
+}
+
+/// 千日手を経由して得られた結果が依存する探索経路。
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PathDependency {
+    /// ルートからこの局面までの経路のハッシュ値
+    pub path_key: Key,
+    /// 結果が依存する経路上の局面のうち、最も浅いもののルートからの深さ
+    pub depth: u32,
+}
+
+impl DfPnEntry {
+    #[inline(always)]
+    pub fn value(&self) -> (u32, u32) {
+        (self.phi, self.delta)
+    }
+
+    /// 手番側の勝ちが確定しているか。
+    #[inline(always)]
+    pub fn is_win(&self) -> bool {
+        self.phi == 0
+    }
+
+    /// 手番側の負けが確定しているか。
+    #[inline(always)]
+    pub fn is_loss(&self) -> bool {
+        self.delta == 0
+    }
+
+    /// このエントリーの結果が持ち駒 (mine, yours) の局面に使えるか。
+    fn covers(&self, mine: Hand, yours: Hand) -> bool {
+        if self.path.is_some() {
+            self.mine == mine && self.yours == yours
+        } else if self.is_win() {
+            hand::is_superior_or_equal(mine, self.mine)
+                && hand::is_superior_or_equal(self.yours, yours)
+        } else if self.is_loss() {
+            hand::is_superior_or_equal(self.mine, mine)
+                && hand::is_superior_or_equal(yours, self.yours)
+        } else {
+            self.mine == mine && self.yours == yours
+        }
+    }
+}
+
+/// [`DfPnEntry`] のうち、バケットのラインに入れる部分。持ち駒は 32 ビットに詰める。
+#[derive(Clone, Copy, Debug)]
+pub struct DfPnHot {
+    phi: u32,
+    delta: u32,
+    mine: u32,
+    yours: u32,
+    /// 探索経路に依存する場合の経路の深さ
+    depth: u32,
+    /// 経路に依存するか、source があるか
+    flags: u8,
+}
+
+/// [`DfPnEntry`] のうち、バケットのラインの外に置く部分。
+#[derive(Clone, Copy, Debug)]
+pub struct DfPnCold {
+    path_key: Key,
+    source: Key,
+}
+
+impl DfPnHot {
+    const PATH: u8 = 1;
+    const SOURCE: u8 = 2;
+}
+
+impl TtValue for DfPnEntry {
+    type Hot = DfPnHot;
+    type Cold = DfPnCold;
+
+    #[inline(always)]
+    fn split(self) -> (DfPnHot, DfPnCold) {
+        let mut flags = 0;
+        if self.path.is_some() {
+            flags |= DfPnHot::PATH;
+        }
+        if self.source.is_some() {
+            flags |= DfPnHot::SOURCE;
+        }
+        let hot = DfPnHot {
+            phi: self.phi,
+            delta: self.delta,
+            mine: hand::pack(self.mine),
+            yours: hand::pack(self.yours),
+            depth: self.path.map_or(0, |path| path.depth),
+            flags,
+        };
+        let cold = DfPnCold {
+            path_key: self.path.map_or(0, |path| path.path_key),
+            source: self.source.unwrap_or(0),
+        };
+        (hot, cold)
+    }
+
+    #[inline(always)]
+    fn join(hot: DfPnHot, cold: DfPnCold) -> Self {
+        DfPnEntry {
+            mine: hand::unpack(hot.mine),
+            yours: hand::unpack(hot.yours),
+            phi: hot.phi,
+            delta: hot.delta,
+            path: (hot.flags & DfPnHot::PATH != 0).then_some(PathDependency {
+                path_key: cold.path_key,
+                depth: hot.depth,
+            }),
+            source: (hot.flags & DfPnHot::SOURCE != 0).then_some(cold.source),
+        }
+    }
+}
+
+// df-pn の表の区画。エントリーが大きいので、1 ラインに 2 エントリーだけ入れる。
+type DfPnShard = Tt<DfPnEntry, BoardKey, 2>;
+
+/// df-pn 用の置換表。盤面と手番をキーとし、持ち駒の優越関係を使って引く。
+///
+/// 複数のスレッドから共有できるように、いくつかの区画に分けてそれぞれをロックで守る。
+pub struct DfPnTable {
+    shards: Vec<Mutex<DfPnShard>>,
+    /// 区画ごとのバケットの配列の位置。先読みはロックを取らずに行う。
+    prefetchers: Vec<Prefetcher<DfPnEntry, 2>>,
+}
+
+impl DfPnTable {
+    /// 区画の個数の上限
+    const MAX_SHARDS: usize = 64;
+    /// 区画がこの割合まで埋まったら、手間の小さいエントリーを取り除く。
+    const GC_OCCUPANCY: f64 = 0.9;
+    /// 1 回の GC で取り除くエントリーの割合
+    const GC_RATIO: f64 = 0.5;
+
+    /// size は 2 ベキでなければならない。
+    pub fn new(size: usize) -> Self {
+        assert!(size.is_power_of_two());
+        let shards = Self::MAX_SHARDS.min(size / 2).max(1);
+        let shards: Vec<_> = (0..shards).map(|_| Tt::new(size / shards)).collect();
+        // 区画のバケットの配列は作り直さないので、位置は表がある限り変わらない。
+        let prefetchers = shards.iter().map(Tt::prefetcher).collect();
+        Self {
+            shards: shards.into_iter().map(Mutex::new).collect(),
+            prefetchers,
+        }
+    }
+
+    /// およそ mb メガバイトに収まる置換表を作る。
+    pub fn with_megabytes(mb: usize) -> Self {
+        Self::new(DfPnShard::size_for_megabytes(mb))
+    }
+
+    /// 使用率 (千分率)
+    pub fn hashfull(&self) -> u32 {
+        let mut len = 0;
+        let mut capacity = 0;
+        for shard in &self.shards {
+            let shard = shard.lock().unwrap_or_else(|error| error.into_inner());
+            len += shard.len();
+            capacity += shard.capacity();
+        }
+        (len * 1000 / capacity) as u32
+    }
+
+    /// 入れられるエントリーの個数
+    pub fn capacity(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| {
+                shard
+                    .lock()
+                    .unwrap_or_else(|error| error.into_inner())
+                    .capacity()
+            })
+            .sum()
+    }
+
+    /// 利用状況の統計。全区画の合計。
+    pub fn stats(&self) -> TtStats {
+        let mut stats = TtStats::default();
+        for shard in &self.shards {
+            stats += shard
+                .lock()
+                .unwrap_or_else(|error| error.into_inner())
+                .stats();
+        }
+        stats
+    }
+
+    // key が入る区画。区画の中の位置は key の下位ビットで決まるので、上位ビットで選ぶ。
+    fn shard(&self, key: Key) -> MutexGuard<'_, DfPnShard> {
+        // 他のスレッドが panic しても表の中身は壊れていない。
+        self.shards[self.shard_index(key)]
+            .lock()
+            .unwrap_or_else(|error| error.into_inner())
+    }
+
+    #[inline(always)]
+    fn shard_index(&self, key: Key) -> usize {
+        (key >> 40) as usize & (self.shards.len() - 1)
+    }
+
+    /// key の局面のバケットをキャッシュに読み込むよう CPU に指示する。ロックは取らない。
+    #[inline]
+    pub fn prefetch(&self, key: &DfPnKey) {
+        self.prefetchers[self.shard_index(key.board)].prefetch(key.board_key());
+    }
+
+    /// key の局面に使えるエントリーを探す。勝ち負けが確定したエントリーを優先する。
+    ///
+    /// 探索経路に依存するエントリーは返さない。
+    pub fn fetch(&self, key: &DfPnKey) -> Option<DfPnEntry> {
+        let shard = self.shard(key.board);
+        let result = Self::find(&shard, key);
+        shard.record_probe(result.is_some());
+        result
+    }
+
+    fn find(shard: &DfPnShard, key: &DfPnKey) -> Option<DfPnEntry> {
+        let mut result = None;
+        for entry in shard.entries(key.board_key()) {
+            if entry.path.is_some() || !entry.covers(key.mine, key.yours) {
+                continue;
+            }
+            if entry.is_win() || entry.is_loss() {
+                return Some(entry);
+            }
+            result = Some(entry);
+        }
+        result
+    }
+
+    /// 経路のハッシュ値が path_key である探索経路上の key の局面に使えるエントリーを探す。
+    ///
+    /// 経路に依存しない勝ち負け、その経路に依存するエントリー、経路に依存しない確定していないエントリーの順に優先する。
+    pub fn fetch_on_path(&self, key: &DfPnKey, path_key: Key) -> Option<DfPnEntry> {
+        let shard = self.shard(key.board);
+        let result = Self::find_on_path(&shard, key, path_key);
+        shard.record_probe(result.is_some());
+        result
+    }
+
+    fn find_on_path(shard: &DfPnShard, key: &DfPnKey, path_key: Key) -> Option<DfPnEntry> {
+        let mut result = None;
+        for entry in shard.entries(key.board_key()) {
+            if !entry.covers(key.mine, key.yours) {
+                continue;
+            }
+            match entry.path {
+                None if entry.is_win() || entry.is_loss() => return Some(entry),
+                None => {
+                    if result.is_none() {
+                        result = Some(entry);
+                    }
+                }
+                Some(path) if path.path_key == path_key => result = Some(entry),
+                Some(_) => {}
+            }
+        }
+        result
+    }
+
+    /// key の局面の値を記録する。
+    ///
+    /// 勝ち負けが確定した値の場合、key の持ち駒は証明駒・反証駒として扱われ、
+    /// それによって不要になるエントリーは取り除かれる。
+    pub fn insert(&self, key: &DfPnKey, value: (u32, u32)) {
+        self.insert_with_source(key, value, None, 1);
+    }
+
+    /// insert と同じだが、phi の値を受け継いだ子ノード source と、値を得るのに探索したノード数 effort も記録する。
+    pub fn insert_with_source(
+        &self,
+        key: &DfPnKey,
+        (phi, delta): (u32, u32),
+        source: Option<Key>,
+        effort: u32,
+    ) {
+        let entry = DfPnEntry {
+            mine: key.mine,
+            yours: key.yours,
+            phi,
+            delta,
+            path: None,
+            source,
+        };
+        self.store(key.board_key(), entry, effort, |old| {
+            (old.mine == entry.mine && old.yours == entry.yours)
+                || ((entry.is_win() || entry.is_loss()) && entry.covers(old.mine, old.yours))
+        });
+    }
+
+    /// 探索経路 path に依存する key の局面の値を記録する。
+    ///
+    /// 持ち駒が一致し、同じ経路上にある局面にしか使われない。経路に依存しないエントリーは残す。
+    pub fn insert_on_path(
+        &self,
+        key: &DfPnKey,
+        (phi, delta): (u32, u32),
+        path: PathDependency,
+        effort: u32,
+    ) {
+        let entry = DfPnEntry {
+            mine: key.mine,
+            yours: key.yours,
+            phi,
+            delta,
+            path: Some(path),
+            source: None,
+        };
+        self.store(key.board_key(), entry, effort, |old| {
+            old.mine == entry.mine
+                && old.yours == entry.yours
+                && old
+                    .path
+                    .is_some_and(|old_path| old_path.path_key == path.path_key)
+        });
+    }
+
+    // 区画に entry を入れ、区画が埋まってきたら GC する。
+    fn store(
+        &self,
+        key: BoardKey,
+        entry: DfPnEntry,
+        effort: u32,
+        replaces: impl Fn(&DfPnEntry) -> bool,
+    ) {
+        let mut shard = self.shard(key.board);
+        shard.insert_by(key, entry, effort, replaces);
+        if shard.len() as f64 >= shard.capacity() as f64 * Self::GC_OCCUPANCY {
+            // 手間が同じなら、詰みか不詰が確定したエントリーを残す。
+            shard.collect_garbage_by(Self::GC_RATIO, |effort, entry| {
+                (effort, entry.is_win() || entry.is_loss())
+            });
+        }
+    }
+
+    /// 中身をすべて書き出す。形式は [`snapshot`] を参照。
+    pub fn save(&self, w: &mut impl Write) -> io::Result<()> {
+        let shards: Vec<_> = self
+            .shards
+            .iter()
+            .map(|shard| shard.lock().unwrap_or_else(|error| error.into_inner()))
+            .collect();
+        let len = shards.iter().map(|shard| shard.len() as u64).sum();
+        snapshot::write_header::<DfPnEntry>(w, len)?;
+        for shard in &shards {
+            for (key, value, effort) in shard.slots() {
+                write_slot(w, key, &value, effort)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// save で書き出したエントリーを読み込んで加える。表の大きさは保存したときと違ってもよい。
+    pub fn load(&self, r: &mut impl Read) -> io::Result<()> {
+        let len = snapshot::read_header::<DfPnEntry>(r)?;
+        for _ in 0..len {
+            let (key, value, effort) = read_slot(r)?;
+            self.store(key, value, effort, |_| false);
+        }
+        Ok(())
+    }
+
+    pub fn clear(&mut self) {
+        for shard in &mut self.shards {
+            shard
+                .get_mut()
+                .unwrap_or_else(|error| error.into_inner())
+                .clear();
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::eval::Value;
+    use shogi_core::{Move, PieceKind};
 
     #[test]
     fn tt_insertion_works_0() {
@@ -81,7 +1042,7 @@ mod tests {
         let value0 = 3;
         let key1 = 5 + 2 * size as u64;
         let value1 = 100;
-        let mut tt = Tt::new(size);
+        let mut tt = Tt::<_, u64>::new(size);
 
         assert_eq!(tt.fetch(key0), None);
         assert_eq!(tt.fetch(key1), None);
@@ -101,10 +1062,436 @@ mod tests {
         let key = 5;
         let value0 = 3;
         let value1 = 100;
-        let mut tt = Tt::new(size);
+        let mut tt = Tt::<_, u64>::new(size);
 
         tt.insert(key, value0);
         tt.insert(key, value1);
         assert_eq!(tt.fetch(key), Some(value1))
     }
+
+    #[test]
+    fn tt_is_sized_in_megabytes() {
+        let tt = EvalTable::with_megabytes(1);
+        let bytes = tt.size() * EvalTable::bucket_bytes();
+        assert!(bytes <= 1 << 20 && bytes > 1 << 19);
+
+        let mut tt = Tt::<_, u64>::new(16);
+        assert_eq!(tt.hashfull(), 0);
+        for key in 0..32 {
+            tt.insert(key, key);
+        }
+        assert_eq!(tt.hashfull(), 500);
+
+        let df_pn = DfPnTable::with_megabytes(1);
+        assert_eq!(df_pn.hashfull(), 0);
+        // 既定の大きさでも、1 エントリー 64 バイトで 26 万エントリー入る。
+        #[cfg(not(feature = "wide-keys"))]
+        assert_eq!(
+            DfPnTable::with_megabytes(DEFAULT_HASH_MB).capacity(),
+            1 << 18
+        );
+    }
+
+    #[test]
+    fn tt_buckets_are_cache_line_aligned() {
+        for size in [2, 16, 1 << 10] {
+            let tt = EvalTable::new(size);
+            assert_eq!(tt.buckets.as_ptr() as usize % 64, 0);
+            let tt = DfPnShard::new(size);
+            assert_eq!(tt.buckets.as_ptr() as usize % 64, 0);
+        }
+        // バケットはちょうど 1 ラインで、キー全体と値の残りはラインの外に置く。
+        assert_eq!(std::mem::size_of::<Bucket<DfPnEntry, 2>>(), 64);
+        assert_eq!(std::mem::size_of::<Bucket<(Value, Option<Move>), 4>>(), 64);
+        assert_eq!(std::mem::size_of::<Bucket<u64, 4>>(), 64);
+        #[cfg(not(feature = "wide-keys"))]
+        let (dfpn_cold, eval_cold) = (64, 32);
+        #[cfg(feature = "wide-keys")]
+        let (dfpn_cold, eval_cold) = (128, 64);
+        assert_eq!(
+            std::mem::size_of::<ColdSlots<BoardKey, DfPnEntry, 2>>(),
+            dfpn_cold
+        );
+        assert_eq!(
+            std::mem::size_of::<ColdSlots<Key, (Value, Option<Move>), 4>>(),
+            eval_cold
+        );
+    }
+
+    #[test]
+    fn df_pn_table_prefetches_without_locking() {
+        let table = DfPnTable::new(1 << 10);
+        let key = DfPnKey {
+            board: 0x1234_5678_9abc,
+            lock: 0,
+            mine: Hand::new(),
+            yours: Hand::new(),
+        };
+        // 区画のロックを持ったままでも先読みできる。
+        let _shard = table.shard(key.board);
+        table.prefetch(&key);
+    }
+
+    #[test]
+    fn tt_evicts_cheapest_entry() {
+        let size = 1 << 4;
+        // すべて同じバケットに入る。
+        let keys: Vec<u64> = (0..9).map(|i| 3 + i * size as u64).collect();
+        let mut tt = Tt::<_, u64>::new(size);
+
+        tt.insert_by(keys[0], 0, 50, |_| true);
+        tt.insert_by(keys[1], 1, 1, |_| true);
+        tt.insert_by(keys[2], 2, 70, |_| true);
+        tt.insert_by(keys[3], 3, 2, |_| true);
+        // 同じキーで入れ直すと手間が足される。
+        tt.insert_by(keys[1], 1, 5, |_| true);
+        assert_eq!(tt.len(), 4);
+
+        // 一杯なら隣のバケットにあふれる。
+        for (i, &key) in keys.iter().enumerate().take(8).skip(4) {
+            tt.insert_by(key, i as u64, 60, |_| true);
+        }
+        assert_eq!(tt.len(), 8);
+        assert_eq!(tt.stats().bucket_full, 0);
+        assert_eq!(tt.fetch(keys[7]), Some(7));
+        // あふれたエントリーも入れ直せる。
+        tt.insert_by(keys[5], 5, 1, |_| true);
+        assert_eq!(tt.len(), 8);
+        assert_eq!(tt.entries(keys[5]).count(), 1);
+
+        tt.insert_by(keys[8], 8, 1, |_| true);
+        assert_eq!(tt.len(), 8);
+        assert_eq!(tt.stats().bucket_full, 1);
+        for (i, &key) in keys.iter().enumerate() {
+            let expected = (i != 3).then_some(i as u64);
+            assert_eq!(tt.fetch(key), expected, "{}", i);
+        }
+    }
+
+    #[test]
+    fn tt_spills_within_group() {
+        let size = 1 << 4;
+        // 1 バケット 2 エントリーなら、4 バケットの組にあふれる。
+        let keys: Vec<u64> = (0..9).map(|i| 3 + i * size as u64).collect();
+        let mut tt = Tt::<_, u64, 2>::new(size);
+        for (i, &key) in keys.iter().enumerate().take(8) {
+            tt.insert_by(key, i as u64, 10 + i as u32, |_| true);
+        }
+        assert_eq!(tt.len(), 8);
+        assert_eq!(tt.stats().bucket_full, 0);
+        for (i, &key) in keys.iter().enumerate().take(8) {
+            assert_eq!(tt.fetch(key), Some(i as u64), "{}", i);
+        }
+
+        // 組が一杯なら、組の中で手間の最も小さいエントリーを追い出す。
+        tt.insert_by(keys[8], 8, 1, |_| true);
+        assert_eq!(tt.len(), 8);
+        assert_eq!(tt.stats().bucket_full, 1);
+        assert_eq!(tt.fetch(keys[0]), None);
+        assert_eq!(tt.fetch(keys[8]), Some(8));
+    }
+
+    #[test]
+    fn tt_counts_probes_and_evictions() {
+        let size = 1 << 4;
+        let mut tt = Tt::<_, u64>::new(size);
+        assert_eq!(tt.fetch(3), None);
+        tt.insert(3, 0);
+        tt.insert(3, 1);
+        assert_eq!(tt.fetch(3), Some(1));
+        // 同じバケットと隣のバケットを一杯にしてから 1 個追い出す。
+        for i in 1..9 {
+            tt.insert(3 + i * size as u64, 0);
+        }
+        assert_eq!(
+            tt.stats(),
+            TtStats {
+                probes: 2,
+                hits: 1,
+                misses: 1,
+                replacements: 1,
+                bucket_full: 1,
+            }
+        );
+        tt.clear();
+        assert_eq!(tt.stats(), TtStats::default());
+
+        let df_pn = DfPnTable::new(16);
+        let key = DfPnKey {
+            board: 0x1234,
+            lock: 0,
+            mine: Hand::new(),
+            yours: Hand::new(),
+        };
+        assert_eq!(df_pn.fetch(&key), None);
+        df_pn.insert(&key, (1, 1));
+        assert!(df_pn.fetch_on_path(&key, 0).is_some());
+        let stats = df_pn.stats();
+        assert_eq!((stats.probes, stats.hits, stats.misses), (2, 1, 1));
+    }
+
+    #[test]
+    fn tt_gc_drops_cheap_entries() {
+        let size = 1 << 6;
+        let mut tt = Tt::<_, u64>::new(size);
+        for key in 0..100u64 {
+            tt.insert_by(key, key, key as u32, |_| true);
+        }
+        assert_eq!(tt.len(), 100);
+
+        tt.collect_garbage(0.5);
+        assert_eq!(tt.len(), 50);
+        for key in 0..100u64 {
+            let expected = if key < 50 { None } else { Some(key) };
+            assert_eq!(tt.fetch(key), expected);
+        }
+
+        // 手間がすべて同じでも、取り除くのは ratio の割合だけ。
+        let mut tt = Tt::<_, u64>::new(size);
+        for key in 0..100u64 {
+            tt.insert_by(key, key, 7, |_| true);
+        }
+        tt.collect_garbage(0.25);
+        assert_eq!(tt.len(), 75);
+        // 手間が同じなら priority で残すものを決める。
+        let mut tt = Tt::<_, u64>::new(size);
+        for key in 0..100u64 {
+            tt.insert_by(key, key, 7, |_| true);
+        }
+        tt.collect_garbage_by(0.5, |effort, &value| (effort, value % 2 == 1));
+        assert_eq!(tt.len(), 50);
+        for key in 0..100u64 {
+            let expected = (key % 2 == 1).then_some(key);
+            assert_eq!(tt.fetch(key), expected);
+        }
+    }
+
+    #[test]
+    fn df_pn_table_uses_hand_superiority() {
+        let board = 0x1234;
+        let one_pawn = hand::from_counts([1, 0, 0, 0, 0, 0, 0]);
+        let two_pawns = hand::from_counts([2, 0, 0, 0, 0, 0, 0]);
+        let rook = hand::from_counts([0, 0, 0, 0, 0, 0, 1]);
+        let tt = DfPnTable::new(16);
+
+        // 手番側が歩 1 枚以上、相手側が飛車 1 枚以下なら手番側の勝ち
+        tt.insert(
+            &DfPnKey {
+                board,
+                lock: 0,
+                mine: one_pawn,
+                yours: rook,
+            },
+            (0, u32::MAX),
+        );
+        let superior = DfPnKey {
+            board,
+            lock: 0,
+            mine: two_pawns,
+            yours: Hand::new(),
+        };
+        assert_eq!(tt.fetch(&superior).map(|e| e.value()), Some((0, u32::MAX)));
+        let inferior = DfPnKey {
+            board,
+            lock: 0,
+            mine: Hand::new(),
+            yours: rook,
+        };
+        assert_eq!(tt.fetch(&inferior), None);
+        let other_board = DfPnKey {
+            board: board + 1,
+            ..superior
+        };
+        assert_eq!(tt.fetch(&other_board), None);
+
+        // 確定していない値は持ち駒が一致するときだけ使う
+        tt.insert(&inferior, (3, 4));
+        assert_eq!(tt.fetch(&inferior).map(|e| e.value()), Some((3, 4)));
+        let more_rooks = DfPnKey {
+            yours: rook.added(PieceKind::Rook).unwrap(),
+            ..inferior
+        };
+        assert_eq!(tt.fetch(&more_rooks), None);
+
+        // 手番側の負けは劣等な局面に使える。劣等な確定していないエントリーは取り除かれる。
+        tt.insert(
+            &DfPnKey {
+                board,
+                lock: 0,
+                mine: Hand::new(),
+                yours: Hand::new(),
+            },
+            (u32::MAX, 0),
+        );
+        assert_eq!(tt.fetch(&inferior).map(|e| e.value()), Some((u32::MAX, 0)));
+        assert_eq!(
+            tt.shard(board).entries(BoardKey { board, lock: 0 }).count(),
+            2
+        );
+    }
+
+    #[test]
+    fn df_pn_table_separates_path_dependent_entries() {
+        let board = 0x1234;
+        let key = DfPnKey {
+            board,
+            lock: 0,
+            mine: Hand::new(),
+            yours: Hand::new(),
+        };
+        let tt = DfPnTable::new(16);
+        let path = PathDependency {
+            path_key: 0x5678,
+            depth: 2,
+        };
+
+        tt.insert(&key, (3, 4));
+        tt.insert_on_path(&key, (u32::MAX, 0), path, 1);
+        // 経路に依存する結果は同じ経路でしか使わない
+        assert_eq!(tt.fetch(&key).map(|e| e.value()), Some((3, 4)));
+        assert_eq!(
+            tt.fetch_on_path(&key, path.path_key).map(|e| e.path),
+            Some(Some(path))
+        );
+        assert_eq!(
+            tt.fetch_on_path(&key, 0x9abc).map(|e| e.value()),
+            Some((3, 4))
+        );
+
+        // 経路に依存しない勝ち負けはどの経路でも優先する
+        tt.insert(&key, (u32::MAX, 0));
+        assert_eq!(
+            tt.fetch_on_path(&key, path.path_key).map(|e| e.path),
+            Some(None)
+        );
+    }
+
+    #[test]
+    fn df_pn_table_keeps_expensive_proofs() {
+        let tt = DfPnTable::new(1 << 4);
+        let proof = DfPnKey {
+            board: 7,
+            lock: 0,
+            mine: Hand::new(),
+            yours: Hand::new(),
+        };
+        // 同じバケットが葉で埋まってから証明が入る。
+        for board in [9, 11, 13] {
+            tt.insert(&DfPnKey { board, ..proof }, (1, 1));
+        }
+        tt.insert_with_source(&proof, (0, u32::MAX), None, 10_000);
+        // 手間の小さい葉で表を何度も埋めても、手間のかかった証明は残る。
+        for board in 15..1000 {
+            let leaf = DfPnKey { board, ..proof };
+            tt.insert(&leaf, (1, 1));
+        }
+        assert_eq!(tt.fetch(&proof).map(|e| e.value()), Some((0, u32::MAX)));
+    }
+
+    #[test]
+    fn tables_survive_save_and_load() {
+        let mut evals = EvalTable::new(16);
+        let mv = Move::Drop {
+            piece: shogi_core::Piece::B_G,
+            to: shogi_core::Square::SQ_5B,
+        };
+        evals.insert(3, (Value(5), Some(mv)));
+        evals.insert(4, (Value(7), None));
+        let mut buf = Vec::new();
+        evals.save(&mut buf).unwrap();
+        let mut loaded = EvalTable::new(64);
+        loaded.load(&mut &buf[..]).unwrap();
+        assert_eq!(loaded.len(), 2);
+        assert_eq!(loaded.fetch(3), Some((Value(5), Some(mv))));
+        assert_eq!(loaded.fetch(4), Some((Value(7), None)));
+
+        let tt = DfPnTable::new(16);
+        let key = DfPnKey {
+            board: 0x1234,
+            lock: 0,
+            mine: hand::from_counts([1, 0, 0, 0, 0, 0, 0]),
+            yours: Hand::new(),
+        };
+        let path = PathDependency {
+            path_key: 99,
+            depth: 3,
+        };
+        tt.insert_with_source(&key, (2, 5), Some(0x55), 1);
+        tt.insert_on_path(&DfPnKey { board: 0x99, ..key }, (u32::MAX, 0), path, 1);
+        let mut buf = Vec::new();
+        tt.save(&mut buf).unwrap();
+        let loaded = DfPnTable::new(1 << 8);
+        loaded.load(&mut &buf[..]).unwrap();
+        let entry = loaded.fetch(&key).unwrap();
+        assert_eq!(entry.value(), (2, 5));
+        assert_eq!(entry.source, Some(0x55));
+        let entry = loaded
+            .fetch_on_path(&DfPnKey { board: 0x99, ..key }, 99)
+            .unwrap();
+        assert_eq!(entry.path, Some(path));
+    }
+
+    #[test]
+    fn load_rejects_foreign_snapshots() {
+        let mut buf = Vec::new();
+        EvalTable::new(16).save(&mut buf).unwrap();
+        // 別の種類の表
+        assert!(DfPnTable::new(16).load(&mut &buf[..]).is_err());
+        // 形式の版
+        let mut other = buf.clone();
+        other[8] ^= 1;
+        assert!(EvalTable::new(16).load(&mut &other[..]).is_err());
+        // Zobrist ハッシュの種
+        let mut other = buf.clone();
+        other[12] ^= 1;
+        assert!(EvalTable::new(16).load(&mut &other[..]).is_err());
+        // キーのビット数
+        let mut other = buf.clone();
+        other[20] ^= 0x18;
+        assert!(EvalTable::new(16).load(&mut &other[..]).is_err());
+        // 途中で切れたファイル
+        assert!(EvalTable::new(16).load(&mut &buf[..10]).is_err());
+        assert!(EvalTable::new(16).load(&mut &buf[..]).is_ok());
+    }
+
+    #[test]
+    fn forced_collisions_are_detected() {
+        use crate::position_wrapper::PositionWrapper;
+        use shogi_core::PartialPosition;
+        use shogi_usi_parser::FromUsi;
+
+        // 盤面のハッシュ値を無理やり一致させた、別の盤面の 2 局面
+        let key = |sfen: &str| {
+            let position = PartialPosition::from_usi(&format!("sfen {}", sfen)).unwrap();
+            PositionWrapper::new(position).df_pn_key()
+        };
+        let key0 = key("4k4/9/4P4/9/9/9/9/9/4K4 b G 1");
+        let key1 = DfPnKey {
+            board: key0.board,
+            ..key("4k4/9/9/4P4/9/9/9/9/4K4 b G 1")
+        };
+        assert_eq!((key0.mine, key0.yours), (key1.mine, key1.yours));
+        assert_ne!(key0.lock, key1.lock);
+
+        // 検査用の値が違うので、別の局面の詰みは使わない。
+        let tt = DfPnTable::new(16);
+        tt.insert(&key0, (0, u32::MAX));
+        assert_eq!(tt.fetch(&key1), None);
+        assert_eq!(tt.fetch_on_path(&key1, 0), None);
+        // 別の局面の値を入れても、もとの局面のエントリーは取り除かれない。
+        tt.insert(&key1, (u32::MAX, 0));
+        assert_eq!(tt.fetch(&key0).map(|e| e.value()), Some((0, u32::MAX)));
+        assert_eq!(tt.fetch(&key1).map(|e| e.value()), Some((u32::MAX, 0)));
+
+        // 128 ビットのキーでは、下位 64 ビットが一致しても別の局面として扱われる。
+        let key0: u128 = 0x1234_5678_9abc_def0;
+        let key1 = key0 | 1 << 100;
+        let mut wide = Tt::<_, u128>::new(16);
+        wide.insert(key0, 1);
+        assert_eq!(wide.fetch(key1), None);
+        wide.insert(key1, 2);
+        assert_eq!(wide.fetch(key0), Some(1));
+        assert_eq!(wide.fetch(key1), Some(2));
+        assert_eq!(wide.entries(key0).count(), 1);
+    }
 }
@@ -0,0 +1,262 @@
+//! 詰将棋の局面として正しいかの検査。
+use shogi_core::{Color, Hand, PartialPosition, Piece, PieceKind, Square};
+
+use crate::{hand, movegen, ErrType, Resolution};
+
+/// 詰将棋の局面として正しいかを調べる。
+///
+/// 手番側を攻め方とする。正しくなければ、見つかった理由をすべて `reason` に並べて
+/// [`Resolution::Invalid`] を返す。
+pub fn validate(position: &PartialPosition) -> Result<(), ErrType> {
+    let reasons = reasons(position);
+    if reasons.is_empty() {
+        return Ok(());
+    }
+    Err(ErrType {
+        resolution: Resolution::Invalid,
+        reason: reasons.join("; "),
+    })
+}
+
+fn reasons(position: &PartialPosition) -> Vec<String> {
+    let mut reasons = vec![];
+    let attacker = position.side_to_move();
+    let defender = attacker.flip();
+    let defender_kings = position
+        .piece_bitboard(Piece::new(PieceKind::King, defender))
+        .count();
+    match defender_kings {
+        0 => reasons.push("defender has no king".to_string()),
+        1 => {
+            let king = position.king_position(defender).unwrap();
+            let occupied = position.occupied_bitboard();
+            if !movegen::attackers_to(position, king, attacker, occupied).is_empty() {
+                reasons.push("defender is already in check".to_string());
+            }
+        }
+        n => reasons.push(format!("defender has {} kings", n)),
+    }
+    for color in Color::all() {
+        let pawn = Piece::new(PieceKind::Pawn, color);
+        for file in 1..=9 {
+            let pawns = (1..=9)
+                .filter(|&rank| position.piece_at(Square::new(file, rank).unwrap()) == Some(pawn))
+                .count();
+            if pawns > 1 {
+                reasons.push(format!(
+                    "{} has {} unpromoted pawns on file {} (nifu)",
+                    color_name(color),
+                    pawns,
+                    file,
+                ));
+            }
+        }
+    }
+    for square in position.occupied_bitboard() {
+        let piece = position.piece_at(square).unwrap();
+        let color = piece.color();
+        let stuck = match piece.piece_kind() {
+            PieceKind::Pawn | PieceKind::Lance => square.relative_rank(color) == 1,
+            PieceKind::Knight => square.relative_rank(color) <= 2,
+            _ => false,
+        };
+        if stuck {
+            reasons.push(format!(
+                "{} {} on {} cannot move",
+                color_name(color),
+                piece_kind_name(piece.piece_kind()),
+                square_name(square),
+            ));
+        }
+    }
+    // 盤上と持ち駒を合わせて 40 枚の組に収まっているか。
+    let on_board = counts_on_board(position);
+    for (index, piece_kind) in Hand::all_hand_pieces().enumerate() {
+        let total = on_board[index]
+            + Color::all()
+                .into_iter()
+                .map(|color| position.hand_of_a_player(color).count(piece_kind).unwrap())
+                .sum::<u8>();
+        if total > hand::MAX_COUNTS[index] {
+            reasons.push(format!(
+                "{} {}s in total (at most {})",
+                total,
+                piece_kind_name(piece_kind),
+                hand::MAX_COUNTS[index],
+            ));
+        }
+    }
+    let kings = position.piece_kind_bitboard(PieceKind::King).count();
+    if kings > 2 {
+        reasons.push(format!("{} kings in total (at most 2)", kings));
+    }
+    reasons
+}
+
+fn color_name(color: Color) -> &'static str {
+    match color {
+        Color::Black => "black",
+        Color::White => "white",
+    }
+}
+
+/// 盤上の駒の各種類の枚数。成駒は成る前の駒として数え、玉は数えない。
+fn counts_on_board(position: &PartialPosition) -> [u8; Hand::NUM_HAND_PIECES] {
+    let mut result = [0; Hand::NUM_HAND_PIECES];
+    for square in position.occupied_bitboard() {
+        let piece_kind = position.piece_at(square).unwrap().piece_kind();
+        let piece_kind = piece_kind.unpromote().unwrap_or(piece_kind);
+        if piece_kind != PieceKind::King {
+            result[piece_kind.array_index()] += 1;
+        }
+    }
+    result
+}
+
+/// 詰将棋の「残り全部」。盤上にも攻め方 (手番側) の持ち駒にもない駒をすべて玉方の持ち駒にする。
+///
+/// 玉方の持ち駒はもとの内容によらず置き換える。駒が 40 枚の組より多ければエラーを返す。
+pub fn fill_defender_hand(position: &mut PartialPosition) -> Result<(), ErrType> {
+    let attacker = position.side_to_move();
+    let on_board = counts_on_board(position);
+    let in_hand = hand::counts(position.hand_of_a_player(attacker));
+    let mut rest = [0; Hand::NUM_HAND_PIECES];
+    let mut reasons = vec![];
+    for (index, piece_kind) in Hand::all_hand_pieces().enumerate() {
+        let used = on_board[index] + in_hand[index];
+        if used > hand::MAX_COUNTS[index] {
+            reasons.push(format!(
+                "{} {}s on the board and in the attacker's hand (at most {})",
+                used,
+                piece_kind_name(piece_kind),
+                hand::MAX_COUNTS[index],
+            ));
+        } else {
+            rest[index] = hand::MAX_COUNTS[index] - used;
+        }
+    }
+    if !reasons.is_empty() {
+        return Err(ErrType {
+            resolution: Resolution::Invalid,
+            reason: reasons.join("; "),
+        });
+    }
+    *position.hand_of_a_player_mut(attacker.flip()) = hand::from_counts(rest);
+    Ok(())
+}
+
+fn piece_kind_name(piece_kind: PieceKind) -> &'static str {
+    match piece_kind {
+        PieceKind::Pawn => "pawn",
+        PieceKind::Lance => "lance",
+        PieceKind::Knight => "knight",
+        PieceKind::Silver => "silver",
+        PieceKind::Gold => "gold",
+        PieceKind::Bishop => "bishop",
+        PieceKind::Rook => "rook",
+        PieceKind::King => "king",
+        _ => unreachable!(),
+    }
+}
+
+// USI の表記 (例: 1a)。
+fn square_name(square: Square) -> String {
+    format!("{}{}", square.file(), (b'a' + square.rank() - 1) as char)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shogi_usi_parser::FromUsi;
+
+    fn reason(sfen: &str) -> Option<String> {
+        let position = PartialPosition::from_usi(&format!("sfen {}", sfen)).unwrap();
+        validate(&position).err().map(|error| {
+            assert!(matches!(error.resolution, Resolution::Invalid));
+            error.reason
+        })
+    }
+
+    #[test]
+    fn valid_positions_pass() {
+        for sfen in [
+            "3g1ks2/6g2/4S4/7B1/9/9/9/9/9 b G2rbg2s4n4l18p 1",
+            "5kgnl/9/4+B1pp1/8p/9/9/9/9/9 b 2S2rb3g2s3n3l15p 1",
+            "7nk/9/6PB1/6NP1/9/9/9/9/9 b P2rb4g4s2n4l15p 1",
+            "8k/9/9/9/9/9/9/9/9 b Rr2b4g4s4n4l18p 1",
+            "lnsgkgsnl/1r5b1/ppppppppp/9/9/9/PPPPPPPPP/1B5R1/LNSGKGSNL b - 1",
+        ] {
+            assert_eq!(reason(sfen), None, "{}", sfen);
+        }
+    }
+
+    #[test]
+    fn invalid_positions_report_reasons() {
+        assert_eq!(
+            reason("9/9/9/9/9/9/9/9/9 b G 1").unwrap(),
+            "defender has no king"
+        );
+        // 後手が攻め方なら玉方は先手。
+        assert_eq!(
+            reason("8k/9/9/9/9/9/9/9/9 w g 1").unwrap(),
+            "defender has no king"
+        );
+        assert_eq!(reason("9/9/9/9/9/9/9/9/K8 w g 1"), None);
+        assert_eq!(
+            reason("7kk/9/9/9/9/9/9/9/9 b G 1").unwrap(),
+            "defender has 2 kings"
+        );
+        assert_eq!(
+            reason("8k/8G/9/9/9/9/9/9/9 b - 1").unwrap(),
+            "defender is already in check"
+        );
+        assert_eq!(
+            reason("8k/9/9/9/9/7P1/7P1/9/9 b - 1").unwrap(),
+            "black has 2 unpromoted pawns on file 2 (nifu)"
+        );
+        // と金は二歩にならない。
+        assert_eq!(reason("8k/9/9/9/9/7+P1/7P1/9/9 b - 1"), None);
+        assert_eq!(
+            reason("N7k/9/9/9/9/9/9/9/8l b - 1").unwrap(),
+            "white lance on 1i cannot move; black knight on 9a cannot move"
+        );
+        assert_eq!(
+            reason("8k/9/9/9/9/9/9/9/9 b 3R 1").unwrap(),
+            "3 rooks in total (at most 2)"
+        );
+        assert_eq!(
+            reason("8k/9/9/9/9/9/9/+B8/9 b 2B 1").unwrap(),
+            "3 bishops in total (at most 2)"
+        );
+    }
+
+    #[test]
+    fn fill_defender_hand_works() {
+        let mut position =
+            PartialPosition::from_usi("sfen 3g1ks2/6g2/4S4/7B1/9/9/9/9/9 b G 1").unwrap();
+        fill_defender_hand(&mut position).unwrap();
+        let expected =
+            PartialPosition::from_usi("sfen 3g1ks2/6g2/4S4/7B1/9/9/9/9/9 b G2rbg2s4n4l18p 1")
+                .unwrap();
+        assert_eq!(position, expected);
+        // 玉方の持ち駒は置き換える。成駒は成る前の駒として数える。
+        let mut position =
+            PartialPosition::from_usi("sfen 5kgnl/9/4+B1pp1/8p/9/9/9/9/9 b 2S3p 1").unwrap();
+        fill_defender_hand(&mut position).unwrap();
+        let expected =
+            PartialPosition::from_usi("sfen 5kgnl/9/4+B1pp1/8p/9/9/9/9/9 b 2S2rb3g2s3n3l15p 1")
+                .unwrap();
+        assert_eq!(position, expected);
+    }
+
+    #[test]
+    fn fill_defender_hand_rejects_too_many_pieces() {
+        let mut position = PartialPosition::from_usi("sfen 8k/9/9/9/9/9/9/9/R8 b 2R5G 1").unwrap();
+        let error = fill_defender_hand(&mut position).unwrap_err();
+        assert_eq!(
+            error.reason,
+            "5 golds on the board and in the attacker's hand (at most 4); \
+             3 rooks on the board and in the attacker's hand (at most 2)"
+        );
+    }
+}
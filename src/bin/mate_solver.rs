@@ -1,17 +1,23 @@
 use std::{
-    collections::BTreeSet,
     env::args,
+    fs::{self, File},
     io::Write,
-    io::{BufRead, BufReader, stdin},
+    io::{self, BufRead, BufReader, BufWriter, stdin},
     process::{Command, Stdio},
+    time::{Duration, Instant},
 };
 
+use mate_solver::Resolution;
+use mate_solver::df_pn::parallel::df_pn_parallel;
 use mate_solver::df_pn::search as dfpnsearch;
-use mate_solver::eval::Value;
 use mate_solver::eval::search as evalsearch;
+use mate_solver::helpmate::search as helpmate;
 use mate_solver::move_ordering::MoveOrderingOptions;
 use mate_solver::position_wrapper::PositionWrapper;
-use mate_solver::tt::{DfPnTable, EvalTable};
+use mate_solver::selfmate;
+use mate_solver::threat::analyze;
+use mate_solver::tt::{DEFAULT_HASH_MB, DfPnTable, EvalTable};
+use mate_solver::validate::{fill_defender_hand, validate};
 use shogi_core::{Move, PartialPosition, Position, ToUsi};
 use shogi_usi_parser::FromUsi;
 
@@ -35,6 +41,17 @@ struct Opts {
     move_format: MoveFormat,
     engine_path: Option<String>,
     move_ordering: MoveOrderingOptions,
+    threads: usize,
+    hash_mb: usize,
+    checkpoint: Option<String>,
+    checkpoint_interval: Duration,
+    resume: Option<String>,
+    fill_defender_hand: bool,
+    analyze: bool,
+    max_nodes: u64,
+    helpmate: bool,
+    max_plies: u32,
+    selfmate: bool,
 }
 
 fn parse_args() -> Opts {
@@ -45,6 +62,17 @@ fn parse_args() -> Opts {
         move_format: MoveFormat::Traditional,
         engine_path: None,
         move_ordering: MoveOrderingOptions::default(),
+        threads: 1,
+        hash_mb: DEFAULT_HASH_MB,
+        checkpoint: None,
+        checkpoint_interval: Duration::from_secs(60),
+        resume: None,
+        fill_defender_hand: false,
+        analyze: false,
+        max_nodes: 100_000,
+        helpmate: false,
+        max_plies: 31,
+        selfmate: false,
     };
     for a in args {
         if a == "--verbose" {
@@ -60,16 +88,85 @@ fn parse_args() -> Opts {
                 "csa" => MoveFormat::Csa,
                 "official" => MoveFormat::Official,
                 "traditional" => MoveFormat::Traditional,
-                _ => panic!(),
+                _ => invalid_argument(&format!("unknown move format: {}", rest)),
             };
         }
         if let Some(rest) = a.strip_prefix("--engine-path=") {
             opts.engine_path = Some(rest.to_owned());
         }
+        if let Some(rest) = a.strip_prefix("--threads=") {
+            opts.threads = rest
+                .parse()
+                .ok()
+                .filter(|&value| value >= 1)
+                .unwrap_or_else(|| invalid_argument(&format!("invalid thread count: {}", rest)));
+        }
+        if let Some(rest) = a.strip_prefix("--hash=") {
+            opts.hash_mb = rest
+                .parse()
+                .ok()
+                .filter(|&value| value >= 1)
+                .unwrap_or_else(|| invalid_argument(&format!("invalid hash size: {}", rest)));
+        }
+        if let Some(rest) = a.strip_prefix("--checkpoint=") {
+            opts.checkpoint = Some(rest.to_owned());
+        }
+        if let Some(rest) = a.strip_prefix("--checkpoint-interval=") {
+            let seconds = rest.parse().ok().filter(|&value| value >= 1);
+            let seconds = seconds.unwrap_or_else(|| {
+                invalid_argument(&format!("invalid checkpoint interval: {}", rest))
+            });
+            opts.checkpoint_interval = Duration::from_secs(seconds);
+        }
+        if let Some(rest) = a.strip_prefix("--resume=") {
+            opts.resume = Some(rest.to_owned());
+        }
+        if a == "--fill-defender-hand" {
+            opts.fill_defender_hand = true;
+        }
+        if a == "--analyze" {
+            opts.analyze = true;
+        }
+        if let Some(rest) = a.strip_prefix("--max-nodes=") {
+            opts.max_nodes = rest
+                .parse()
+                .ok()
+                .filter(|&value| value >= 1)
+                .unwrap_or_else(|| invalid_argument(&format!("invalid node limit: {}", rest)));
+        }
+        if a == "--helpmate" {
+            opts.helpmate = true;
+        }
+        if let Some(rest) = a.strip_prefix("--max-plies=") {
+            opts.max_plies = rest
+                .parse()
+                .ok()
+                .filter(|&value| value >= 1)
+                .unwrap_or_else(|| invalid_argument(&format!("invalid ply limit: {}", rest)));
+        }
+        if a == "--selfmate" {
+            opts.selfmate = true;
+        }
     }
     opts
 }
 
+fn print_usage() {
+    eprintln!("usage: mate_solver [options] < sfen");
+    eprintln!("  --verbose  --output=json  --move-format=<traditional|official|kif|usi|csa>");
+    eprintln!("  --threads=<n>  --hash=<MB>  --engine-path=<path>");
+    eprintln!("  --checkpoint=<path>  --checkpoint-interval=<seconds>  --resume=<path>");
+    eprintln!("  --fill-defender-hand  --analyze  --helpmate  --selfmate");
+    eprintln!("  --max-nodes=<n>  --max-plies=<n>");
+}
+
+// 引数が正しくなければ、理由と使い方を出力して終了コード 1 で終わる。
+fn invalid_argument(reason: &str) -> ! {
+    eprintln!("{}", reason);
+    print_usage();
+    std::process::exit(1);
+}
+
 fn invoke_external_engine(
     position: &PartialPosition,
     exec_path: &str,
@@ -121,69 +218,67 @@ go
     Some(moves.to_vec())
 }
 
-fn find_mate_sequence(
-    df_pn: &mut DfPnTable,
-    evals: &mut EvalTable,
-    position: &PartialPosition,
-    opt: Value,
-    opts: &Opts,
-) -> Vec<Move> {
-    let mut turn = 0;
-    let mut beta = opt.plies_added_unchecked(1);
-    let mut position = PositionWrapper::new(position.clone());
-    let mut result = Vec::new();
-    loop {
-        let mut ctx = evalsearch::SearchCtx::default();
-        let (_value, mv) = if turn % 2 == 0 {
-            evalsearch::alpha_beta_me_with_options(
-                &position,
-                df_pn,
-                evals,
-                Value::ZERO,
-                beta,
-                &mut BTreeSet::new(),
-                &mut ctx,
-                opts.verbose,
-                &opts.move_ordering,
-            )
-        } else {
-            evalsearch::alpha_beta_you_with_options(
-                &position,
-                df_pn,
-                evals,
-                Value::ZERO,
-                beta,
-                &mut BTreeSet::new(),
-                &mut ctx,
-                opts.verbose,
-                &opts.move_ordering,
-            )
-        };
-        if let Some(mv) = mv {
-            result.push(mv);
-            position.make_move(mv);
-        } else {
-            return result;
-        }
-        turn += 1;
-        beta = beta.plies_added_unchecked(-1);
-    }
+// df-pn の表、評価値の表の順に 1 つのファイルに書き出す。途中で止まっても前のファイルは壊さない。
+fn save_checkpoint(path: &str, df_pn: &DfPnTable, eval: &EvalTable) -> io::Result<()> {
+    let tmp = format!("{}.tmp", path);
+    let mut w = BufWriter::new(File::create(&tmp)?);
+    df_pn.save(&mut w)?;
+    eval.save(&mut w)?;
+    w.into_inner()?.sync_all()?;
+    fs::rename(&tmp, path)
 }
 
-fn solve_myself(position: &PartialPosition, opts: &Opts) -> Option<Vec<Move>> {
-    let size = 1 << 16;
-
-    let mut df_pn = DfPnTable::new(size);
+fn load_checkpoint(path: &str, df_pn: &DfPnTable, eval: &mut EvalTable) -> io::Result<()> {
+    let mut r = BufReader::new(File::open(path)?);
+    df_pn.load(&mut r)?;
+    eval.load(&mut r)
+}
 
-    let mut eval = EvalTable::new(size);
-    let mate_result = dfpnsearch::df_pn_with_options(
-        &mut df_pn,
-        &PositionWrapper::new(position.clone()),
-        opts.verbose,
-        &opts.move_ordering,
-    );
+fn solve_myself(position: &PartialPosition, opts: &Opts) -> Option<Vec<Move>> {
+    let mut df_pn = DfPnTable::with_megabytes(opts.hash_mb);
+    let mut eval = EvalTable::with_megabytes(opts.hash_mb);
+    if let Some(path) = &opts.resume {
+        if let Err(error) = load_checkpoint(path, &df_pn, &mut eval) {
+            eprintln!("cannot resume from {}: {}", path, error);
+            std::process::exit(1);
+        }
+        if opts.verbose {
+            eprintln!("! resumed from {}", path);
+        }
+    }
+    let position_wrapper = PositionWrapper::new(position.clone());
+    let mate_result = loop {
+        // チェックポイントを取るときは一定時間ごとに探索を打ち切って表を書き出す。
+        let limits = dfpnsearch::SearchLimits {
+            deadline: opts
+                .checkpoint
+                .as_ref()
+                .map(|_| Instant::now() + opts.checkpoint_interval),
+            ..Default::default()
+        };
+        let outcome = df_pn_parallel(
+            &df_pn,
+            &position_wrapper,
+            opts.verbose,
+            &mut dfpnsearch::SearchStats::default(),
+            &opts.move_ordering,
+            &Default::default(),
+            &limits,
+            opts.threads,
+        );
+        if let Some(path) = &opts.checkpoint {
+            if let Err(error) = save_checkpoint(path, &df_pn, &eval) {
+                eprintln!("cannot write checkpoint {}: {}", path, error);
+            } else if opts.verbose {
+                eprintln!("! checkpoint written to {}", path);
+            }
+        }
+        if !matches!(outcome, dfpnsearch::Outcome::Unknown(_)) || limits.deadline.is_none() {
+            break outcome;
+        }
+    };
     // 不詰。
-    if mate_result == (u32::MAX, 0) {
+    if mate_result == dfpnsearch::Outcome::NoMate {
         return None;
     }
     let result = evalsearch::search_with_options(
@@ -193,14 +288,194 @@ fn solve_myself(position: &PartialPosition, opts: &Opts) -> Option<Vec<Move>> {
         opts.verbose,
         &opts.move_ordering,
     );
+    if let Some(path) = &opts.checkpoint {
+        if let Err(error) = save_checkpoint(path, &df_pn, &eval) {
+            eprintln!("cannot write checkpoint {}: {}", path, error);
+        }
+    }
     if opts.verbose {
         eprintln!("! result = {:?}", result);
+        eprintln!(
+            "! hashfull = df-pn {} eval {}",
+            df_pn.hashfull(),
+            eval.hashfull()
+        );
+        eprintln!("! df-pn tt = {:?}", df_pn.stats());
+        eprintln!("! eval tt = {:?}", eval.stats());
     }
     if !result.is_mate() {
         return None;
     }
-    let sequence = find_mate_sequence(&mut df_pn, &mut eval, position, result, opts);
-    Some(sequence)
+    evalsearch::mate_sequence(
+        position,
+        &mut df_pn,
+        &mut eval,
+        result,
+        opts.verbose,
+        &opts.move_ordering,
+        &dfpnsearch::SearchLimits::default(),
+    )
+}
+
+fn format_move(position: &PartialPosition, mv: Move, opts: &Opts) -> String {
+    match opts.move_format {
+        MoveFormat::Usi => mv.to_usi_owned(),
+        MoveFormat::Official => shogi_official_kifu::display_single_move(position, mv).unwrap(),
+        MoveFormat::Traditional => {
+            shogi_official_kifu::display_single_move_kansuji(position, mv).unwrap()
+        }
+        _ => todo!(),
+    }
+}
+
+fn json_array(items: &[String]) -> String {
+    let items: Vec<_> = items.iter().map(|item| format!("{:?}", item)).collect();
+    format!("[{}]", items.join(","))
+}
+
+// 詰めろと必至を調べて出力する。
+fn print_analysis(position: &PartialPosition, opts: &Opts) {
+    let limits = dfpnsearch::SearchLimits {
+        max_nodes: Some(opts.max_nodes),
+        ..Default::default()
+    };
+    let analysis = match analyze(position, opts.hash_mb, &limits) {
+        Ok(analysis) => analysis,
+        Err(error) => {
+            eprintln!("invalid position: {}", error.reason);
+            std::process::exit(1);
+        }
+    };
+    // 詰めろの手順は手番側が手を渡した局面から始まる。
+    let mut passed = position.clone();
+    passed.side_to_move_set(position.side_to_move().flip());
+    let mut threat_line = vec![];
+    for &mv in &analysis.threat_line {
+        threat_line.push(format_move(&passed, mv, opts));
+        passed.make_move(mv).unwrap();
+    }
+    let hissi: Vec<_> = analysis
+        .hissi
+        .iter()
+        .map(|&mv| format_move(position, mv, opts))
+        .collect();
+    let hissi_unknown: Vec<_> = analysis
+        .hissi_unknown
+        .iter()
+        .map(|&mv| format_move(position, mv, opts))
+        .collect();
+    let threat = match analysis.threat {
+        dfpnsearch::Outcome::Mate => "mate",
+        dfpnsearch::Outcome::NoMate => "nomate",
+        dfpnsearch::Outcome::Unknown(_) => "unknown",
+    };
+    match opts.output {
+        Output::Text => {
+            if threat_line.is_empty() {
+                println!("threat: {}", threat);
+            } else {
+                println!("threat:");
+                for (index, move_str) in threat_line.iter().enumerate() {
+                    println!("{:2}: {}", index + 1, move_str);
+                }
+            }
+            if hissi.is_empty() {
+                println!("hissi: none");
+            } else {
+                println!("hissi: {}", hissi.join(" "));
+            }
+            if !hissi_unknown.is_empty() {
+                println!("hissi unknown: {}", hissi_unknown.join(" "));
+            }
+        }
+        Output::Json => println!(
+            "{{\"threat\":{:?},\"threat_line\":{},\"hissi\":{},\"hissi_unknown\":{}}}",
+            threat,
+            json_array(&threat_line),
+            json_array(&hissi),
+            json_array(&hissi_unknown),
+        ),
+    }
+}
+
+// 協力詰の最短手順と、それが唯一かを出力する。
+fn print_helpmate(position: &PartialPosition, opts: &Opts) {
+    let limits = dfpnsearch::SearchLimits {
+        max_nodes: Some(opts.max_nodes),
+        ..Default::default()
+    };
+    let outcome =
+        helpmate::search_with_limits(position, opts.max_plies, &mut Default::default(), &limits);
+    let (moves, unique) = match outcome {
+        helpmate::Outcome::Mate { moves, unique } => (moves, unique),
+        helpmate::Outcome::NoMate => {
+            println!("nomate");
+            return;
+        }
+        helpmate::Outcome::Unknown => {
+            println!("unknown");
+            return;
+        }
+    };
+    let mut position = position.clone();
+    let mut move_strs = vec![];
+    for &mv in &moves {
+        move_strs.push(format_move(&position, mv, opts));
+        position.make_move(mv).unwrap();
+    }
+    match opts.output {
+        Output::Text => {
+            for (index, move_str) in move_strs.iter().enumerate() {
+                println!("{:2}: {}", index + 1, move_str);
+            }
+            println!("{}", if unique { "unique" } else { "not unique" });
+        }
+        Output::Json => println!(
+            "{{\"moves\":{},\"unique\":{}}}",
+            json_array(&move_strs),
+            unique
+        ),
+    }
+}
+
+// 自玉詰を解いて、詰将棋と同じ形式で出力する。
+fn print_selfmate(position: &PartialPosition, opts: &Opts) {
+    let limits = dfpnsearch::SearchLimits {
+        max_nodes: Some(opts.max_nodes),
+        ..Default::default()
+    };
+    match selfmate::solve(position, opts.hash_mb, &limits) {
+        Ok((dfpnsearch::Outcome::Mate, moves)) => print_moves(position, &moves, opts),
+        Ok((dfpnsearch::Outcome::NoMate, _)) => println!("nomate"),
+        Ok((dfpnsearch::Outcome::Unknown(_), _)) => println!("unknown"),
+        Err(error) if matches!(error.resolution, Resolution::Unknown) => println!("unknown"),
+        Err(error) => {
+            eprintln!("invalid position: {}", error.reason);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn print_moves(position: &PartialPosition, moves: &[Move], opts: &Opts) {
+    let mut position = position.clone();
+    let mut first = true;
+    if opts.output == Output::Json {
+        print!("[");
+    }
+    for (index, &mv) in moves.iter().enumerate() {
+        let move_str = format_move(&position, mv, opts);
+        match opts.output {
+            Output::Text => println!("{:2}: {}", index + 1, move_str),
+            Output::Json => {
+                print!("{}{:?}", if first { "" } else { "," }, move_str);
+                first = false;
+            }
+        }
+        position.make_move(mv).unwrap();
+    }
+    if opts.output == Output::Json {
+        println!("]");
+    }
 }
 
 // Take an SFEN string from stdin, and solves the problem.
@@ -211,40 +486,45 @@ fn main() {
     if opts.verbose {
         eprintln!("! sfen = {}", sfen.trim());
     }
-    let mut position = PartialPosition::from_usi(&("sfen ".to_string() + sfen.trim())).unwrap();
+    let mut position = match PartialPosition::from_usi(&("sfen ".to_string() + sfen.trim())) {
+        Ok(position) => position,
+        Err(error) => {
+            eprintln!("invalid sfen: {:?}", error);
+            std::process::exit(1);
+        }
+    };
+    if opts.fill_defender_hand {
+        if let Err(error) = fill_defender_hand(&mut position) {
+            eprintln!("invalid position: {}", error.reason);
+            std::process::exit(1);
+        }
+        if opts.verbose {
+            eprintln!("! filled sfen = {}", position.to_sfen_owned());
+        }
+    }
+    if let Err(error) = validate(&position) {
+        eprintln!("invalid position: {}", error.reason);
+        std::process::exit(1);
+    }
+    if opts.analyze {
+        print_analysis(&position, &opts);
+        return;
+    }
+    if opts.helpmate {
+        print_helpmate(&position, &opts);
+        return;
+    }
+    if opts.selfmate {
+        print_selfmate(&position, &opts);
+        return;
+    }
     let moves = if let Some(ref exec_path) = opts.engine_path {
         invoke_external_engine(&position, exec_path, &opts)
     } else {
         solve_myself(&position, &opts)
     };
     if let Some(moves) = moves {
-        let mut first = true;
-        if opts.output == Output::Json {
-            print!("[");
-        }
-        for (index, &mv) in moves.iter().enumerate() {
-            let move_str = match opts.move_format {
-                MoveFormat::Usi => mv.to_usi_owned(),
-                MoveFormat::Official => {
-                    shogi_official_kifu::display_single_move(&position, mv).unwrap()
-                }
-                MoveFormat::Traditional => {
-                    shogi_official_kifu::display_single_move_kansuji(&position, mv).unwrap()
-                }
-                _ => todo!(),
-            };
-            match opts.output {
-                Output::Text => println!("{:2}: {}", index + 1, move_str),
-                Output::Json => {
-                    print!("{}{:?}", if first { "" } else { "," }, move_str);
-                    first = false;
-                }
-            }
-            position.make_move(mv).unwrap();
-        }
-        if opts.output == Output::Json {
-            println!("]");
-        }
+        print_moves(&position, &moves, &opts);
     } else {
         println!("nomate");
     }
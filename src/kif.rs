@@ -74,6 +74,10 @@ pub fn parse_kif(a: &str) -> PartialPosition {
         if let Some(rest) = line.strip_prefix("後手の持駒：") {
             *position.hand_of_a_player_mut(Color::White) = parse_hand(rest);
         }
+        // 後手が攻め方の問題。
+        if line.trim_end() == "後手番" {
+            position.side_to_move_set(Color::White);
+        }
         if let Some(rest) = line.strip_prefix('|') {
             let mut v = Vec::new();
             let mut color = Color::Black;
@@ -113,4 +117,14 @@ mod tests {
         assert_eq!(parse_hand("銀"), hands[0]);
         assert_eq!(parse_hand("飛二　金四　銀　桂三　香三　歩十五"), hands[1]);
     }
+
+    #[test]
+    fn parse_kif_reads_side_to_move() {
+        let kif = "後手の持駒：金\n  ９ ８ ７ ６ ５ ４ ３ ２ １\n+---------------------------+\n|・・・・・・・・・|一\n|・・・・・・・・・|二\n|・・・・・・・・・|三\n|・・・・・・・・・|四\n|・・・・・・・・・|五\n|・・・・・・・・・|六\n|・・・・・・・・・|七\n|・・・・・・・・・|八\n| 玉・・・・・・・・|九\n+---------------------------+\n先手の持駒：なし\n";
+        let position = parse_kif(kif);
+        assert_eq!(position.side_to_move(), Color::Black);
+        let position = parse_kif(&format!("{}後手番\n", kif));
+        assert_eq!(position.side_to_move(), Color::White);
+        assert_eq!(position.to_sfen_owned(), "9/9/9/9/9/9/9/9/K8 w g 1");
+    }
 }